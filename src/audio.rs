@@ -0,0 +1,35 @@
+use ::gameboy::SAMPLE_RATE;
+use ::sdl2::{
+    Sdl,
+    audio::{AudioQueue, AudioSpecDesired},
+};
+
+// drop samples instead of letting the latency grow when emulation runs ahead
+const MAX_QUEUED_BYTES: u32 = SAMPLE_RATE / 10 * 2 * 4;
+
+pub struct Audio {
+    queue: AudioQueue<f32>,
+}
+
+impl Audio {
+    pub fn new(sdl: &Sdl) -> Result<Audio, String> {
+        let audio = sdl.audio()?;
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        let queue = audio.open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+        Ok(Self { queue })
+    }
+
+    // Takes interleaved stereo samples
+    pub fn queue(&mut self, samples: &[f32]) {
+        if self.queue.size() < MAX_QUEUED_BYTES {
+            self.queue
+                .queue_audio(samples)
+                .expect("failed to queue audio");
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use super::headless;
+use super::log::Level;
+use ::std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: gameboy-emulator [OPTIONS] ROM
+       gameboy-emulator info [--json] ROM
+
+options:
+  --bootrom PATH       boot through the given DMG boot ROM instead of skipping it
+  --scale N            window scale, 1 to 16 (default 4)
+  --fullscreen         start in fullscreen
+  --paused             start paused, P toggles pause
+  --speed X            emulation speed multiplier (default 1.0)
+  --save-dir DIR       keep .sav files in DIR instead of next to the ROM
+  --rewind-budget MIB  memory for up to 60 seconds of rewind, 0 turns it off
+                       (default 32)
+  --lenient            load ROMs with a bad header checksum or size
+  --log-level LEVEL    error, warn, info or debug (default info)
+  --debug              stop at the first instruction and read debugger commands
+                       from stdin, type help there for the list
+  --headless           run without a window, see below
+  -h, --help           print this help
+
+headless options:
+  --frames N           frames to run (default 600)
+  --until ADDR=VAL     stop once the byte at ADDR is VAL, both in hex
+  --screenshot PATH    write the last frame to a PNG file
+
+info options:
+  --json               print the header as JSON
+
+keys:
+  arrows, X, Z         D-pad, A, B
+  Return, Right Shift  Start, Select
+  P                    pause
+  Backspace            hold to rewind
+  F1 to F4             load the save state in slot 1 to 4
+  Shift+F1 to F4       save the state to slot 1 to 4
+";
+
+pub enum Command {
+    Run(Options),
+    Info { rom: PathBuf, json: bool },
+    Help,
+}
+
+pub struct Options {
+    pub rom: PathBuf,
+    pub bootrom: Option<PathBuf>,
+    pub scale: u32,
+    pub fullscreen: bool,
+    pub paused: bool,
+    pub speed: f64,
+    pub save_dir: Option<PathBuf>,
+    pub lenient: bool,
+    // MiB
+    pub rewind_budget: usize,
+    pub log_level: Level,
+    pub debug: bool,
+    // headless when set
+    pub headless: Option<headless::Options>,
+}
+
+// Splits --flag=value so that both forms are accepted
+fn split(arg: String) -> (String, Option<String>) {
+    match arg.split_once('=') {
+        Some((flag, val)) if flag.starts_with("--") => (flag.to_string(), Some(val.to_string())),
+        _ => (arg, None),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, val: &str, what: &str) -> Result<T, String> {
+    val.parse()
+        .map_err(|_| format!("{} expects {}, got '{}'", flag, what, val))
+}
+
+fn parse_condition(s: &str) -> Option<(u16, u8)> {
+    let (addr, val) = s.split_once('=')?;
+    Some((
+        u16::from_str_radix(addr.trim_start_matches("0x"), 16).ok()?,
+        u8::from_str_radix(val.trim_start_matches("0x"), 16).ok()?,
+    ))
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("info") {
+        args.next();
+        return parse_info(args);
+    }
+
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        bootrom: None,
+        scale: 4,
+        fullscreen: false,
+        paused: false,
+        speed: 1.0,
+        save_dir: None,
+        lenient: false,
+        rewind_budget: 32,
+        log_level: Level::Info,
+        debug: false,
+        headless: None,
+    };
+    let mut headless = false;
+    let mut headless_options = headless::Options::default();
+    // headless options given without --headless, for the error message
+    let mut headless_only = None;
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = split(arg);
+        let mut value = |what: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} expects {}", flag, what))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--bootrom" => options.bootrom = Some(value("a path")?.into()),
+            "--scale" => {
+                let what = "a number from 1 to 16";
+                options.scale = parse_value(&flag, &value(what)?, what)?;
+                if !(1..=16).contains(&options.scale) {
+                    return Err(format!("--scale expects {}", what));
+                }
+            }
+            "--fullscreen" => options.fullscreen = true,
+            "--paused" => options.paused = true,
+            "--speed" => {
+                let what = "a positive number";
+                options.speed = parse_value(&flag, &value(what)?, what)?;
+                if !(options.speed.is_finite() && options.speed > 0.0) {
+                    return Err(format!("--speed expects {}", what));
+                }
+            }
+            "--save-dir" => options.save_dir = Some(value("a directory")?.into()),
+            "--lenient" => options.lenient = true,
+            "--rewind-budget" => {
+                let what = "a number of MiB";
+                options.rewind_budget = parse_value(&flag, &value(what)?, what)?;
+            }
+            "--log-level" => {
+                let what = format!("one of {}", Level::NAMES.join(", "));
+                let val = value(&what)?;
+                options.log_level = Level::parse(&val)
+                    .ok_or_else(|| format!("--log-level expects {}, got '{}'", what, val))?;
+            }
+            "--debug" => options.debug = true,
+            "--headless" => headless = true,
+            "--frames" => {
+                headless_options.frames = parse_value(&flag, &value("a number")?, "a number")?;
+                headless_only.get_or_insert("--frames");
+            }
+            "--until" => {
+                let what = "ADDR=VAL in hex";
+                let val = value(what)?;
+                headless_options.until = Some(
+                    parse_condition(&val)
+                        .ok_or_else(|| format!("--until expects {}, got '{}'", what, val))?,
+                );
+                headless_only.get_or_insert("--until");
+            }
+            "--screenshot" => {
+                headless_options.screenshot = Some(value("a path")?.into());
+                headless_only.get_or_insert("--screenshot");
+            }
+            _ if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown option {}", flag));
+            }
+            _ => {
+                if rom.is_some() {
+                    return Err(format!("unexpected argument {}", flag));
+                }
+                rom = Some(flag);
+            }
+        }
+    }
+
+    if let (false, Some(flag)) = (headless, headless_only) {
+        return Err(format!("{} only applies with --headless", flag));
+    }
+    if headless && options.debug {
+        return Err("--debug needs a window, it does not apply with --headless".to_string());
+    }
+    options.rom = rom.ok_or("no ROM given")?.into();
+    options.headless = headless.then_some(headless_options);
+    Ok(Command::Run(options))
+}
+
+fn parse_info(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut rom = None;
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--json" => json = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown option {}", flag));
+            }
+            _ => {
+                if rom.is_some() {
+                    return Err(format!("unexpected argument {}", arg));
+                }
+                rom = Some(arg);
+            }
+        }
+    }
+    Ok(Command::Info {
+        rom: rom.ok_or("no ROM given")?.into(),
+        json,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    fn run(line: &str) -> Options {
+        match parse_line(line) {
+            Ok(Command::Run(options)) => options,
+            Ok(_) => panic!("'{}' is not a run command", line),
+            Err(e) => panic!("'{}': {}", line, e),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match parse_line(line) {
+            Err(e) => e,
+            Ok(_) => panic!("'{}' was accepted", line),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let options = run("game.gb");
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.bootrom, None);
+        assert_eq!(options.scale, 4);
+        assert_eq!(options.speed, 1.0);
+        assert_eq!(options.rewind_budget, 32);
+        assert_eq!(options.log_level, Level::Info);
+        assert!(!options.fullscreen && !options.paused && !options.lenient && !options.debug);
+        assert!(options.headless.is_none());
+    }
+
+    #[test]
+    fn values_are_accepted_separate_or_inline() {
+        let options = run("--scale 2 --speed=1.5 game.gb --bootrom=dmg.bin --save-dir saves");
+        assert_eq!(options.scale, 2);
+        assert_eq!(options.speed, 1.5);
+        assert_eq!(options.bootrom, Some(PathBuf::from("dmg.bin")));
+        assert_eq!(options.save_dir, Some(PathBuf::from("saves")));
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+    }
+
+    #[test]
+    fn flags() {
+        let options = run("--fullscreen --paused --lenient --debug --log-level debug game.gb");
+        assert!(options.fullscreen && options.paused && options.lenient && options.debug);
+        assert_eq!(options.log_level, Level::Debug);
+        assert_eq!(run("--rewind-budget 0 game.gb").rewind_budget, 0);
+    }
+
+    #[test]
+    fn headless_options() {
+        let options = run("--headless --frames 10 --until 0xC000=0x01 --screenshot out.png a.gb");
+        let headless = options.headless.unwrap();
+        assert_eq!(headless.frames, 10);
+        assert_eq!(headless.until, Some((0xC000, 0x01)));
+        assert_eq!(headless.screenshot, Some(PathBuf::from("out.png")));
+        assert_eq!(run("--headless a.gb").headless.unwrap().frames, 600);
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert_eq!(
+            error("--scale 0 a.gb"),
+            "--scale expects a number from 1 to 16"
+        );
+        assert_eq!(
+            error("--scale x a.gb"),
+            "--scale expects a number from 1 to 16, got 'x'"
+        );
+        assert_eq!(
+            error("--speed -1 a.gb"),
+            "--speed expects a positive number"
+        );
+        assert_eq!(
+            error("--speed inf a.gb"),
+            "--speed expects a positive number"
+        );
+        assert_eq!(
+            error("--headless --until C000 a.gb"),
+            "--until expects ADDR=VAL in hex, got 'C000'"
+        );
+        assert!(error("--log-level loud a.gb").starts_with("--log-level expects one of"));
+        assert_eq!(error("a.gb --bootrom"), "--bootrom expects a path");
+    }
+
+    #[test]
+    fn rejects_bad_combinations() {
+        assert_eq!(error(""), "no ROM given");
+        assert_eq!(error("a.gb b.gb"), "unexpected argument b.gb");
+        assert_eq!(error("--turbo a.gb"), "unknown option --turbo");
+        assert_eq!(
+            error("--frames 10 a.gb"),
+            "--frames only applies with --headless"
+        );
+        assert!(error("--headless --debug a.gb").starts_with("--debug needs a window"));
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(parse_line("a.gb --help"), Ok(Command::Help)));
+        assert!(matches!(parse_line("-h"), Ok(Command::Help)));
+        assert!(matches!(parse_line("info -h"), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn info() {
+        match parse_line("info --json a.gb") {
+            Ok(Command::Info { rom, json }) => {
+                assert_eq!(rom, PathBuf::from("a.gb"));
+                assert!(json);
+            }
+            _ => panic!("not an info command"),
+        }
+        assert!(matches!(
+            parse_line("info a.gb"),
+            Ok(Command::Info { json: false, .. })
+        ));
+        assert_eq!(error("info"), "no ROM given");
+        assert_eq!(error("info --verbose a.gb"), "unknown option --verbose");
+    }
+}
@@ -0,0 +1,476 @@
+use ::gameboy::{Access, GameBoy, Registers, WatchHit, Watchpoint};
+use ::std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+const HELP: &str = "\
+commands:
+  s, step [N]          execute N instructions (default 1)
+  n, next              step over a CALL or RST
+  f, finish            run until the current routine returns
+  c, continue          run until a breakpoint or pause
+  p, pause             stop a running emulation
+  b, break ADDR        set a breakpoint
+  d, delete [ADDR]     delete a breakpoint, or all of them
+  w, watch [r|w|rw] ADDR[-END] [VAL]
+                       stop on reads and/or writes (default w) in ADDR to END,
+                       optionally only of the value VAL
+  uw, unwatch [N]      delete watchpoint N, or all of them
+  l, list              list breakpoints and watchpoints
+  r, regs              show registers and flags
+  set REG VAL          set a register (a f b c d e h l af bc de hl sp pc) or flag (zf nf hf cf)
+  x ADDR [LEN]         hexdump LEN bytes (default 64)
+  q, quit              quit the emulator
+  h, help              print this help
+addresses and values are hex";
+
+// What poll wants the main loop to do
+pub enum Poll {
+    Idle,
+    // the emulation advanced while stopped, so the frame buffer may have changed
+    Redraw,
+    Quit,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Stopped,
+    Running,
+    // stop at the next instruction boundary
+    Pausing,
+    // stop when PC reaches the instruction following a CALL or RST
+    StepOver(u16),
+    // stop after a return that pops the frame active when finish was entered
+    Finish(u16),
+}
+
+pub struct Debugger {
+    commands: Receiver<String>,
+    breakpoints: BTreeSet<u16>,
+    state: State,
+    // opcode of the instruction being executed while running
+    opcode: u8,
+    // reported once the instruction that made the access is done
+    hit: Option<WatchHit>,
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    u16::from_str_radix(s.trim_start_matches("0x").trim_start_matches('$'), 16).ok()
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC)
+}
+
+fn is_rst(opcode: u8) -> bool {
+    opcode & 0xC7 == 0xC7
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+impl Debugger {
+    // Commands are read on a separate thread so that the SDL window keeps being serviced
+    pub fn new(stopped: bool) -> Self {
+        let (sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            commands,
+            breakpoints: BTreeSet::new(),
+            state: if stopped {
+                State::Stopped
+            } else {
+                State::Running
+            },
+            opcode: 0,
+            hit: None,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        matches!(self.state, State::Stopped)
+    }
+
+    // Prints the state once when the emulation starts stopped
+    pub fn start(&self, gameboy: &GameBoy) {
+        if self.is_stopped() {
+            print_registers(gameboy);
+            prompt();
+        }
+    }
+
+    // Handles the commands typed since the last call
+    pub fn poll(&mut self, gameboy: &mut GameBoy) -> Poll {
+        let mut ret = Poll::Idle;
+        loop {
+            let line = match self.commands.try_recv() {
+                Ok(line) => line,
+                // a closed stdin leaves the emulation as it is
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return ret,
+            };
+            match self.execute(gameboy, &line) {
+                Ok(Poll::Quit) => return Poll::Quit,
+                Ok(Poll::Redraw) => ret = Poll::Redraw,
+                Ok(Poll::Idle) => (),
+                Err(e) => println!("{}", e),
+            }
+            if self.is_stopped() {
+                prompt();
+            }
+        }
+    }
+
+    // Called after every cycle while running. Returns true when the emulation has to stop.
+    pub fn check(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.is_stopped() {
+            return false;
+        }
+        if let Some(hit) = gameboy.take_watch_hit() {
+            self.hit = Some(hit);
+        }
+        // a locked CPU never reaches another boundary, so only stop for the lock-up or a pause
+        if gameboy.is_locked() {
+            let lockup = gameboy.take_lockup();
+            if lockup.is_none() && !matches!(self.state, State::Pausing) {
+                return false;
+            }
+            if let Some(hit) = self.hit.take() {
+                print_hit(&hit);
+            }
+            if let Some(lockup) = lockup {
+                println!("{}", lockup);
+            }
+            self.state = State::Stopped;
+            print_registers(gameboy);
+            prompt();
+            return true;
+        }
+        if !gameboy.at_instruction_boundary() {
+            return false;
+        }
+        let regs = gameboy.registers();
+        let opcode = self.opcode;
+        self.opcode = gameboy.peek(regs.pc);
+        let stop = match self.state {
+            State::Stopped | State::Running => false,
+            State::Pausing => true,
+            State::StepOver(pc) => regs.pc == pc,
+            State::Finish(sp) => is_return(opcode) && regs.sp > sp,
+        };
+        let breakpoint = self.breakpoints.contains(&regs.pc);
+        let hit = self.hit.take();
+        if !stop && !breakpoint && hit.is_none() {
+            return false;
+        }
+        if let Some(hit) = hit {
+            print_hit(&hit);
+        }
+        if breakpoint {
+            println!("breakpoint at {:04X}", regs.pc);
+        }
+        self.state = State::Stopped;
+        print_registers(gameboy);
+        prompt();
+        true
+    }
+
+    // Leaves the stopped state, the current instruction is only checked at the next boundary
+    fn resume(&mut self, gameboy: &GameBoy, state: State) {
+        self.hit = None;
+        self.opcode = gameboy.peek(gameboy.registers().pc);
+        self.state = state;
+    }
+
+    fn execute(&mut self, gameboy: &mut GameBoy, line: &str) -> Result<Poll, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Poll::Idle);
+        };
+        let args = words.collect::<Vec<_>>();
+        let addr = |i: usize| -> Result<u16, String> {
+            let arg = args.get(i).ok_or("missing address")?;
+            parse_hex(arg).ok_or_else(|| format!("bad address '{}'", arg))
+        };
+        let running = !self.is_stopped();
+        match command {
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(Poll::Quit),
+            "p" | "pause" => {
+                if running {
+                    self.state = State::Pausing;
+                }
+            }
+            "b" | "break" => {
+                let addr = addr(0)?;
+                self.breakpoints.insert(addr);
+                println!("breakpoint at {:04X}", addr);
+            }
+            "d" | "delete" if args.is_empty() => self.breakpoints.clear(),
+            "d" | "delete" => {
+                let addr = addr(0)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {:04X}", addr));
+                }
+            }
+            "w" | "watch" => {
+                let watchpoint = parse_watchpoint(&args)?;
+                println!(
+                    "watchpoint {}: {}",
+                    gameboy.watchpoints().len(),
+                    describe(&watchpoint)
+                );
+                gameboy.add_watchpoint(watchpoint);
+            }
+            "uw" | "unwatch" => match args.first() {
+                None => while gameboy.remove_watchpoint(0).is_some() {},
+                Some(arg) => {
+                    let idx = arg.parse().map_err(|_| format!("bad index '{}'", arg))?;
+                    gameboy
+                        .remove_watchpoint(idx)
+                        .ok_or_else(|| format!("no watchpoint {}", idx))?;
+                }
+            },
+            "l" | "list" => {
+                for addr in &self.breakpoints {
+                    println!("breakpoint {:04X}", addr);
+                }
+                for (i, watchpoint) in gameboy.watchpoints().iter().enumerate() {
+                    println!("watchpoint {}: {}", i, describe(watchpoint));
+                }
+            }
+            "x" => {
+                let start = addr(0)?;
+                let len = match args.get(1) {
+                    Some(arg) => parse_hex(arg).ok_or_else(|| format!("bad length '{}'", arg))?,
+                    None => 0x40,
+                };
+                hexdump(gameboy, start, len);
+            }
+            _ if running => return Err("running, pause first".to_string()),
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(arg) => arg.parse().map_err(|_| format!("bad count '{}'", arg))?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    gameboy.step();
+                    if let Some(hit) = gameboy.take_watch_hit() {
+                        print_hit(&hit);
+                        break;
+                    }
+                    if let Some(lockup) = gameboy.take_lockup() {
+                        println!("{}", lockup);
+                        break;
+                    }
+                }
+                print_registers(gameboy);
+                return Ok(Poll::Redraw);
+            }
+            "n" | "next" => {
+                let regs = gameboy.registers();
+                let opcode = gameboy.peek(regs.pc);
+                if is_call(opcode) {
+                    self.resume(gameboy, State::StepOver(regs.pc.wrapping_add(3)));
+                } else if is_rst(opcode) {
+                    self.resume(gameboy, State::StepOver(regs.pc.wrapping_add(1)));
+                } else {
+                    gameboy.step();
+                    if let Some(hit) = gameboy.take_watch_hit() {
+                        print_hit(&hit);
+                    }
+                    if let Some(lockup) = gameboy.take_lockup() {
+                        println!("{}", lockup);
+                    }
+                    print_registers(gameboy);
+                    return Ok(Poll::Redraw);
+                }
+            }
+            "f" | "finish" => {
+                let sp = gameboy.registers().sp;
+                self.resume(gameboy, State::Finish(sp));
+            }
+            "c" | "continue" => self.resume(gameboy, State::Running),
+            "r" | "regs" => print_registers(gameboy),
+            "set" => {
+                let (Some(reg), Some(val)) = (args.first(), args.get(1)) else {
+                    return Err("usage: set REG VAL".to_string());
+                };
+                let val = parse_hex(val).ok_or_else(|| format!("bad value '{}'", val))?;
+                let mut regs = gameboy.registers();
+                set_register(&mut regs, reg, val)?;
+                gameboy.set_registers(regs);
+                print_registers(gameboy);
+            }
+            _ => return Err(format!("unknown command '{}', try help", command)),
+        }
+        Ok(Poll::Idle)
+    }
+}
+
+fn set_register(regs: &mut Registers, reg: &str, val: u16) -> Result<(), String> {
+    let byte = || u8::try_from(val).map_err(|_| format!("{} takes a byte", reg));
+    let flag = || match val {
+        0 | 1 => Ok(val == 1),
+        _ => Err(format!("{} takes 0 or 1", reg)),
+    };
+    match reg {
+        "a" => regs.a = byte()?,
+        "f" => regs.write_af(u16::from_be_bytes([regs.a, byte()?])),
+        "b" => regs.b = byte()?,
+        "c" => regs.c = byte()?,
+        "d" => regs.d = byte()?,
+        "e" => regs.e = byte()?,
+        "h" => regs.h = byte()?,
+        "l" => regs.l = byte()?,
+        "af" => regs.write_af(val),
+        "bc" => regs.write_bc(val),
+        "de" => regs.write_de(val),
+        "hl" => regs.write_hl(val),
+        "sp" => regs.sp = val,
+        "pc" => regs.pc = val,
+        "zf" => regs.set_zf(flag()?),
+        "nf" => regs.set_nf(flag()?),
+        "hf" => regs.set_hf(flag()?),
+        "cf" => regs.set_cf(flag()?),
+        _ => return Err(format!("unknown register '{}'", reg)),
+    }
+    Ok(())
+}
+
+// [r|w|rw] ADDR[-END] [VAL]
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let (read, write, args) = match args.first() {
+        Some(&"r") => (true, false, &args[1..]),
+        Some(&"w") => (false, true, &args[1..]),
+        Some(&"rw") => (true, true, &args[1..]),
+        _ => (false, true, args),
+    };
+    let range = args.first().ok_or("missing address")?;
+    let bad_range = || format!("bad address '{}'", range);
+    let range = match range.split_once('-') {
+        Some((start, end)) => {
+            parse_hex(start).ok_or_else(bad_range)?..=parse_hex(end).ok_or_else(bad_range)?
+        }
+        None => {
+            let addr = parse_hex(range).ok_or_else(bad_range)?;
+            addr..=addr
+        }
+    };
+    if range.is_empty() {
+        return Err(bad_range());
+    }
+    let value = match args.get(1) {
+        Some(arg) => Some(
+            parse_hex(arg)
+                .and_then(|v| u8::try_from(v).ok())
+                .ok_or_else(|| format!("bad value '{}'", arg))?,
+        ),
+        None => None,
+    };
+    if args.len() > 2 {
+        return Err("usage: watch [r|w|rw] ADDR[-END] [VAL]".to_string());
+    }
+    Ok(Watchpoint {
+        range,
+        read,
+        write,
+        value,
+    })
+}
+
+fn describe(watchpoint: &Watchpoint) -> String {
+    let access = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "rw",
+        (true, false) => "r",
+        _ => "w",
+    };
+    let mut ret = format!("{} {:04X}", access, watchpoint.range.start());
+    if watchpoint.range.start() != watchpoint.range.end() {
+        ret += &format!("-{:04X}", watchpoint.range.end());
+    }
+    if let Some(value) = watchpoint.value {
+        ret += &format!(" = {:02X}", value);
+    }
+    ret
+}
+
+fn print_hit(hit: &WatchHit) {
+    let (access, preposition) = match hit.access {
+        Access::Read => ("read", "from"),
+        Access::Write => ("write", "to"),
+    };
+    let bank = match hit.bank {
+        Some(bank) => format!(" (bank {:02X})", bank),
+        None => String::new(),
+    };
+    println!(
+        "watchpoint: {} {:02X} {} {:04X}{} by the instruction at {:04X}",
+        access, hit.value, preposition, hit.addr, bank, hit.pc
+    );
+}
+
+fn print_registers(gameboy: &GameBoy) {
+    let regs = gameboy.registers();
+    let flag = |set: bool, c: char| if set { c } else { '-' };
+    let bytes = (0..3)
+        .map(|i| format!("{:02X}", gameboy.peek(regs.pc.wrapping_add(i))))
+        .collect::<Vec<_>>();
+    println!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} {}{}{}{} [{}]{}",
+        regs.af(),
+        regs.bc(),
+        regs.de(),
+        regs.hl(),
+        regs.sp,
+        regs.pc,
+        flag(regs.zf(), 'Z'),
+        flag(regs.nf(), 'N'),
+        flag(regs.hf(), 'H'),
+        flag(regs.cf(), 'C'),
+        bytes.join(" "),
+        if gameboy.is_locked() {
+            " locked"
+        } else if gameboy.is_stopped() {
+            " stopped"
+        } else if gameboy.is_halted() {
+            " halted"
+        } else {
+            ""
+        },
+    );
+}
+
+fn hexdump(gameboy: &GameBoy, start: u16, len: u16) {
+    for line in (0..len).step_by(16) {
+        let addr = start.wrapping_add(line);
+        let bytes = (0..16.min(len - line))
+            .map(|i| gameboy.peek(addr.wrapping_add(i)))
+            .collect::<Vec<_>>();
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = bytes
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect::<String>();
+        println!("{:04X}  {:<47}  {}", addr, hex, ascii);
+    }
+}
+
+fn prompt() {
+    print!("(gb) ");
+    let _ = io::stdout().flush();
+}
@@ -1,56 +1,342 @@
 mod cartridge;
 mod cpu;
+mod joypad;
 mod peripherals;
+mod rewind;
+mod state;
 mod timer;
+mod watchpoint;
 
-pub use self::cartridge::Cartridge;
+pub use self::cartridge::{Cartridge, CartridgeError, CartridgeHeader, global_checksum};
 use self::cpu::Cpu;
+pub use self::cpu::{Lockup, Registers};
+pub use self::joypad::Button;
 pub use self::peripherals::Bootrom;
 use self::peripherals::Peripherals;
-use self::timer::Timer;
-use ::sdl2::{Sdl, event::Event};
-use ::std::time;
+pub use self::peripherals::{LCD_HEIGHT, LCD_PIXELS, LCD_WIDTH, SAMPLE_RATE};
+pub use self::rewind::RewindOptions;
+use self::rewind::{Back, Rewind};
+use self::state::Sections;
+pub use self::state::StateError;
+pub use self::watchpoint::{Access, WatchHit, Watchpoint};
 
-const CPU_CLOCK_HZ: u128 = 4_194_304;
-const M_CYCLE_CLOCK: u128 = 4;
-const M_CYCLE_NANOS: u128 = M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;
+pub const CPU_CLOCK_HZ: u128 = 4_194_304;
+pub const M_CYCLE_CLOCK: u128 = 4;
+pub const M_CYCLE_NANOS: u128 = M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;
+// 154 lines of 114 M-cycles
+pub const FRAME_M_CYCLES: u128 = 154 * 114;
 
 pub struct GameBoy {
     cpu: Cpu,
     peripherals: Peripherals,
-    sdl: Sdl,
+    rewind: Option<Rewind>,
+    // a lock-up the frontend has not been told about yet
+    lockup: Option<Lockup>,
 }
 
 impl GameBoy {
-    pub fn new(bootrom: Bootrom, cartridge: Cartridge) -> Self {
-        let cpu = Cpu::new();
-        let sdl = sdl2::init().expect("failed to initialize SDL");
-        let peripherals = Peripherals::new(bootrom, cartridge, &sdl);
-        Self {
-            cpu,
-            sdl,
-            peripherals,
+    // Without a boot ROM the hardware starts in the state the boot ROM would leave it in
+    pub fn new(bootrom: Option<Bootrom>, cartridge: Cartridge) -> Self {
+        let skip_bootrom = bootrom.is_none();
+        let mut ret = Self {
+            cpu: Cpu::new(),
+            peripherals: Peripherals::new(bootrom, cartridge),
+            rewind: None,
+            lockup: None,
+        };
+        if skip_bootrom {
+            let header_checksum = ret.peripherals.cartridge.read(0x014D);
+            ret.cpu.skip_bootrom(header_checksum);
+            ret.peripherals.skip_bootrom(&mut ret.cpu.interrupts);
         }
+        // start at an instruction boundary with the first opcode prefetched
+        ret.cpu.fetch(&ret.peripherals);
+        ret
     }
 
-    pub fn run(&mut self) {
-        let mut event_pump = self.sdl.event_pump().unwrap();
-        let time = time::Instant::now();
-        let mut emulated: u128 = 0;
-        'running: loop {
-            let elapsed = time.elapsed().as_nanos();
-            for _ in 0..(elapsed - emulated) / M_CYCLE_NANOS {
-                for event in event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. } => break 'running,
-                        _ => (),
-                    }
-                }
-                self.cpu.emulate_cycle(&mut self.peripherals);
-                self.peripherals.timer.emulate_cycle(&mut self.cpu.interrupts);
-                self.peripherals.ppu.emulate_cycle();
-                emulated += M_CYCLE_NANOS;
+    // Emulates one M-cycle. Returns true when a frame has been completed.
+    pub fn emulate_cycle(&mut self) -> bool {
+        self.peripherals.watchpoints.pc = self.cpu.instruction_pc();
+        let stopped = self.cpu.is_stopped();
+        let locked = self.cpu.lockup().is_some();
+        self.cpu.emulate_cycle(&mut self.peripherals);
+        if !locked && let Some(lockup) = self.cpu.lockup() {
+            self.lockup = Some(lockup);
+        }
+        let frame = if self.cpu.is_stopped() {
+            // STOP stops the system clock, only the RTC on the cartridge keeps its own time.
+            // The blank LCD is delivered as one frame.
+            self.peripherals.cartridge.emulate_cycle();
+            if !stopped {
+                self.peripherals.ppu.blank();
+            }
+            !stopped
+        } else {
+            self.peripherals.emulate_dma_cycle(&self.cpu.interrupts);
+            self.peripherals
+                .timer
+                .emulate_cycle(&mut self.cpu.interrupts);
+            let div = self.peripherals.timer.div();
+            self.peripherals.apu.emulate_cycle(div);
+            self.peripherals.cartridge.emulate_cycle();
+            self.peripherals.ppu.emulate_cycle(&mut self.cpu.interrupts)
+        };
+        if frame && self.rewind.as_mut().is_some_and(Rewind::frame) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(state);
+        }
+        frame
+    }
+
+    // Emulates until the next instruction starts, or for at most a frame's worth of cycles so
+    // that a CPU halted without pending interrupts cannot hang the caller. Returns true when a
+    // frame has been completed.
+    pub fn step(&mut self) -> bool {
+        let mut frame = false;
+        for _ in 0..FRAME_M_CYCLES {
+            frame |= self.emulate_cycle();
+            if self.cpu.at_instruction_boundary() {
+                break;
             }
         }
+        frame
+    }
+
+    // The CPU is waiting for an interrupt, which lets a frontend tell idle time apart
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+
+    // Stopped until a button is pressed, with a blank LCD
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.is_stopped()
+    }
+
+    // Hung by an illegal opcode, the rest of the hardware keeps running
+    pub fn is_locked(&self) -> bool {
+        self.cpu.lockup().is_some()
+    }
+
+    // Reported once when the CPU locks up. A locked CPU never reaches another instruction
+    // boundary.
+    pub fn take_lockup(&mut self) -> Option<Lockup> {
+        self.lockup.take()
+    }
+
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cpu.at_instruction_boundary()
+    }
+
+    // PC is the address of the next instruction
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    // Changing PC prefetches from the new address
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.cpu.set_registers(&self.peripherals, regs);
+    }
+
+    // See state.rs for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = state::Writer::new(self.peripherals.cartridge.checksum());
+        w.section(b"CPU ", |w| self.cpu.save_state(w));
+        self.peripherals.save_state(&mut w);
+        w.finish()
+    }
+
+    // Nothing is changed when the state is rejected
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let checksum = self.peripherals.cartridge.checksum();
+        let sections = Sections::parse(data, checksum)?;
+        // a state with a valid header can still be corrupt halfway through
+        let backup = self.save_state();
+        if let Err(e) = self.load_sections(&sections) {
+            let backup = Sections::parse(&backup, checksum).expect("own state is valid");
+            self.load_sections(&backup).expect("own state is valid");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_sections(&mut self, sections: &Sections) -> Result<(), StateError> {
+        sections.load(b"CPU ", |r| self.cpu.load_state(r))?;
+        self.peripherals.load_state(sections)
+    }
+
+    // None turns rewinding off and drops the history
+    pub fn set_rewind(&mut self, options: Option<RewindOptions>) {
+        self.rewind = options.map(Rewind::new);
+    }
+
+    // Steps one frame back in time, which shows an older frame every few calls as snapshots are
+    // taken every few frames. Returns false once the history is exhausted.
+    pub fn rewind_frame(&mut self) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+        let ret = match rewind.back() {
+            Back::Stay => true,
+            Back::Exhausted => false,
+            Back::Restore(state) => {
+                self.load_state(state).expect("rewind snapshots are valid");
+                true
+            }
+        };
+        self.rewind = Some(rewind);
+        ret
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.peripherals.watchpoints.list
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.peripherals.watchpoints.list.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, idx: usize) -> Option<Watchpoint> {
+        let list = &mut self.peripherals.watchpoints.list;
+        (idx < list.len()).then(|| list.remove(idx))
+    }
+
+    // The first watchpoint hit since the last call. Instruction fetches never hit.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.peripherals.watchpoints.take_hit()
+    }
+
+    // One shade per pixel: 0xFF (white), 0xAA, 0x55 or 0x00 (black)
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.peripherals.ppu.buffer()
+    }
+
+    // Interleaved stereo samples at SAMPLE_RATE produced since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.peripherals.apu.take_samples()
+    }
+
+    // Reads memory without affecting the emulation
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.peripherals.peek(&self.cpu.interrupts, addr)
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.peripherals.cartridge.rumble()
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.peripherals.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.peripherals.cartridge
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.peripherals
+            .joypad
+            .press(&mut self.cpu.interrupts, button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.peripherals.joypad.release(button);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cartridge::tests::rom;
+    use super::*;
+
+    fn gameboy(rom: Box<[u8]>) -> GameBoy {
+        GameBoy::new(None, Cartridge::from_bytes(rom).unwrap())
+    }
+
+    fn run(gameboy: &mut GameBoy, cycles: u128) {
+        for _ in 0..cycles {
+            gameboy.emulate_cycle();
+        }
+    }
+
+    // Replaces the tag of a section, which leaves the data parseable
+    fn rename_section(data: &mut [u8], from: &[u8; 4], to: &[u8; 4]) {
+        let pos = data.windows(4).position(|w| w == from).unwrap();
+        data[pos..pos + 4].copy_from_slice(to);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut gb = gameboy(rom(0x00, 0));
+        run(&mut gb, 1000);
+        let state = gb.save_state();
+        run(&mut gb, FRAME_M_CYCLES);
+        assert_ne!(gb.save_state(), state);
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+    }
+
+    #[test]
+    fn state_round_trip_after_oam_dma() {
+        let mut rom = rom(0x00, 0).into_vec();
+        // LD A,$C0; LDH ($46),A; JR -2
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0xC0, 0xE0, 0x46, 0x18, 0xFE]);
+        let mut gb = gameboy(rom.into());
+        run(&mut gb, 1000);
+        let state = gb.save_state();
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+    }
+
+    #[test]
+    fn rejected_state_leaves_the_emulation_unchanged() {
+        let mut gb = gameboy(rom(0x00, 0));
+        let mut state = gb.save_state();
+        run(&mut gb, FRAME_M_CYCLES);
+        let before = gb.save_state();
+        // everything up to the cartridge loads before the missing section is noticed
+        rename_section(&mut state, b"CART", b"XXXX");
+        assert_eq!(
+            gb.load_state(&state),
+            Err(StateError::MissingSection("CART".to_string()))
+        );
+        assert_eq!(gb.save_state(), before);
+    }
+
+    // Offset of the payload of a section
+    fn section(data: &[u8], tag: &[u8; 4]) -> usize {
+        data.windows(4).position(|w| w == tag).unwrap() + 8
+    }
+
+    #[test]
+    fn rejects_impossible_values() {
+        let mut gb = gameboy(rom(0x00, 0));
+        run(&mut gb, 1000);
+        let state = gb.save_state();
+        // registers, interrupts, HALT, STOP, the lock-up and the in-flight opcode come first
+        let cpu_step = section(&state, b"CPU ") + 24;
+        // the mode, registers and the STAT line come first
+        let ppu_cycles = section(&state, b"PPU ") + 13;
+        for (pos, val, error) in [
+            (cpu_step, 3, "CPU step"),
+            (ppu_cycles, 0, "PPU cycles"),
+            (ppu_cycles, 115, "PPU cycles"),
+        ] {
+            let mut corrupt = state.clone();
+            corrupt[pos] = val;
+            assert_eq!(gb.load_state(&corrupt), Err(StateError::Invalid(error)));
+            assert_eq!(gb.save_state(), state);
+        }
+    }
+
+    #[test]
+    fn rejects_states_of_other_cartridges() {
+        // a code byte, header changes would cancel out in the global checksum
+        let mut other = rom(0x00, 0).into_vec();
+        other[0x150] = 0x01;
+        let other = gameboy(other.into()).save_state();
+        let mut gb = gameboy(rom(0x00, 0));
+        let before = gb.save_state();
+        assert_eq!(gb.load_state(&other), Err(StateError::WrongCartridge));
+        assert_eq!(gb.save_state(), before);
     }
 }
@@ -1,8 +1,47 @@
 use super::peripherals::mbc::Mbc;
+use super::state::{Reader, StateError, Writer};
+use ::std::fmt;
+
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Truncated { len: usize },
+    BadChecksum { expected: u8, actual: u8 },
+    UnknownMbc(u8),
+    UnknownRomSize(u8),
+    UnknownSramSize(u8),
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Truncated { len } => write!(
+                f,
+                "file is {} bytes, too short to hold a cartridge header",
+                len
+            ),
+            Self::BadChecksum { expected, actual } => write!(
+                f,
+                "header checksum is {:02X} but the header sums to {:02X}",
+                expected, actual
+            ),
+            Self::UnknownMbc(code) => write!(f, "unsupported cartridge type {:02X}", code),
+            Self::UnknownRomSize(code) => write!(f, "unknown ROM size code {:02X}", code),
+            Self::UnknownSramSize(code) => write!(f, "unknown SRAM size code {:02X}", code),
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "header declares {} bytes of ROM but the file has {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
 
-#[repr(C)]
 pub struct CartridgeHeader {
-    entry_point: [u8; 4],
     logo: [u8; 48],
     title: [u8; 11],
     maker: [u8; 4],
@@ -20,99 +59,350 @@ pub struct CartridgeHeader {
 }
 
 impl CartridgeHeader {
-    fn new(data: [u8; 0x50]) -> Self {
-        let ret = unsafe { std::mem::transmute::<[u8; 0x50], Self>(data) };
-        let mut checksum: u8 = 0;
-        for i in 0x34..=0x4c {
-            checksum = checksum.wrapping_sub(data[i]).wrapping_sub(1);
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::Truncated { len: rom.len() });
         }
-        assert!(
-            checksum == ret.header_checksum[0],
-            "Checksum validation failed."
-        );
-        ret
+        // the lengths of the ranges match the field types
+        Ok(Self {
+            logo: rom[0x104..0x134].try_into().unwrap(),
+            title: rom[0x134..0x13F].try_into().unwrap(),
+            maker: rom[0x13F..0x143].try_into().unwrap(),
+            cgb_flag: rom[0x143..0x144].try_into().unwrap(),
+            new_licensee: rom[0x144..0x146].try_into().unwrap(),
+            sgb_flag: rom[0x146..0x147].try_into().unwrap(),
+            cartridge_type: rom[0x147..0x148].try_into().unwrap(),
+            rom_size: rom[0x148..0x149].try_into().unwrap(),
+            sram_size: rom[0x149..0x14A].try_into().unwrap(),
+            destination: rom[0x14A..0x14B].try_into().unwrap(),
+            old_licensee: rom[0x14B..0x14C].try_into().unwrap(),
+            game_version: rom[0x14C..0x14D].try_into().unwrap(),
+            header_checksum: rom[0x14D..0x14E].try_into().unwrap(),
+            global_checksum: rom[0x14E..0x150].try_into().unwrap(),
+        })
     }
 
-    fn rom_size(&self) -> usize {
-        assert!(
-            self.rom_size[0] <= 0x80,
-            "Invalid rom size {}.",
-            self.rom_size[0]
-        );
-        return 1 << (15 + self.rom_size[0]);
+    // 0x134-0x14C summed the way the boot ROM does
+    pub fn checksum(&self) -> u8 {
+        [
+            &self.title[..],
+            &self.maker,
+            &self.cgb_flag,
+            &self.new_licensee,
+            &self.sgb_flag,
+            &self.cartridge_type,
+            &self.rom_size,
+            &self.sram_size,
+            &self.destination,
+            &self.old_licensee,
+            &self.game_version,
+        ]
+        .concat()
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_sub(*b).wrapping_sub(1))
+    }
+
+    fn validate_checksum(&self) -> Result<(), CartridgeError> {
+        let actual = self.checksum();
+        if actual != self.header_checksum[0] {
+            return Err(CartridgeError::BadChecksum {
+                expected: self.header_checksum[0],
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum[0]
     }
 
-    fn sram_size(&self) -> usize {
+    pub fn global_checksum(&self) -> u16 {
+        u16::from_be_bytes(self.global_checksum)
+    }
+
+    // The boot ROM refuses to start cartridges without the exact logo
+    pub fn has_valid_logo(&self) -> bool {
+        self.logo == NINTENDO_LOGO
+    }
+
+    pub fn title(&self) -> String {
+        String::from_utf8_lossy(&self.title)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    pub fn maker(&self) -> String {
+        String::from_utf8_lossy(&self.maker)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    pub fn cgb_flag(&self) -> u8 {
+        self.cgb_flag[0]
+    }
+
+    pub fn new_licensee(&self) -> String {
+        String::from_utf8_lossy(&self.new_licensee).to_string()
+    }
+
+    pub fn sgb_flag(&self) -> u8 {
+        self.sgb_flag[0]
+    }
+
+    pub fn cartridge_type(&self) -> u8 {
+        self.cartridge_type[0]
+    }
+
+    pub fn rom_size_code(&self) -> u8 {
+        self.rom_size[0]
+    }
+
+    pub fn sram_size_code(&self) -> u8 {
+        self.sram_size[0]
+    }
+
+    pub fn destination(&self) -> u8 {
+        self.destination[0]
+    }
+
+    pub fn old_licensee(&self) -> u8 {
+        self.old_licensee[0]
+    }
+
+    pub fn game_version(&self) -> u8 {
+        self.game_version[0]
+    }
+
+    pub fn rom_size(&self) -> Result<usize, CartridgeError> {
+        match self.rom_size[0] {
+            code @ 0x00..=0x08 => Ok(0x8000 << code),
+            code => Err(CartridgeError::UnknownRomSize(code)),
+        }
+    }
+
+    pub fn sram_size(&self) -> Result<usize, CartridgeError> {
         match self.sram_size[0] {
-            0x00 => 0,
-            0x01 => 0x800,
-            0x02 => 0x2000,
-            0x03 => 0x8000,
-            0x04 => 0x20000,
-            0x05 => 0x10000,
-            _ => panic!("Invalid sram size {}.", self.sram_size[0]),
+            0x00 => Ok(0),
+            0x01 => Ok(0x800),
+            0x02 => Ok(0x2000),
+            0x03 => Ok(0x8000),
+            0x04 => Ok(0x20000),
+            0x05 => Ok(0x10000),
+            code => Err(CartridgeError::UnknownSramSize(code)),
         }
     }
 }
 
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// Sum of every byte of the ROM except the global checksum itself
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(i, 0x14E | 0x14F))
+        .fold(0u16, |acc, (_, b)| acc.wrapping_add(*b as u16))
+}
+
+// MBC1M compilations are 1 MiB with more games, and so Nintendo logos, at 256 KiB boundaries
+fn is_multicart(rom: &[u8]) -> bool {
+    rom.len() == 0x100000
+        && (1..4).any(|i| {
+            let base = i * 0x40000 + 0x104;
+            rom[base..base + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+        })
+}
+
 pub struct Cartridge {
+    title: String,
     rom: Box<[u8]>,
+    // computed once as save states are tagged with it
+    checksum: u16,
     sram: Box<[u8]>,
     mbc: Mbc,
+    battery: bool,
+    sram_dirty: bool,
 }
 
 impl Cartridge {
-    pub fn new(rom: Box<[u8]>) -> Self {
-        let header = CartridgeHeader::new(rom[0x100..0x150].try_into().unwrap());
-        let title = str::from_utf8(&header.title)
-            .unwrap()
-            .trim_end_matches('\0')
-            .to_string();
-        let rom_size = header.rom_size();
-        let sram_size = header.sram_size();
+    pub fn from_bytes(rom: Box<[u8]>) -> Result<Self, CartridgeError> {
+        Self::parse(rom, false).map(|(ret, _)| ret)
+    }
+
+    // Tolerates a bad checksum or unknown sizes, and pads or truncates the ROM to the declared
+    // size so that overdumped or hacked ROMs still load. Returns the problems it let through.
+    pub fn from_bytes_lenient(
+        rom: Box<[u8]>,
+    ) -> Result<(Self, Vec<CartridgeError>), CartridgeError> {
+        Self::parse(rom, true)
+    }
+
+    fn parse(
+        mut rom: Box<[u8]>,
+        lenient: bool,
+    ) -> Result<(Self, Vec<CartridgeError>), CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let mut warnings = Vec::new();
+        let mut warn_or = |e: CartridgeError| {
+            if !lenient {
+                return Err(e);
+            }
+            warnings.push(e);
+            Ok(())
+        };
+
+        if let Err(e) = header.validate_checksum() {
+            warn_or(e)?;
+        }
+        let rom_size = match header.rom_size() {
+            Ok(rom_size) => rom_size,
+            Err(e) => {
+                warn_or(e)?;
+                rom.len().next_power_of_two().max(0x8000)
+            }
+        };
+        if rom.len() != rom_size {
+            warn_or(CartridgeError::SizeMismatch {
+                expected: rom_size,
+                actual: rom.len(),
+            })?;
+            let mut resized = rom.into_vec();
+            resized.resize(rom_size, 0xFF);
+            rom = resized.into();
+        }
+
         let rom_banks = rom_size >> 14;
-        let mbc = Mbc::new(header.cartridge_type[0], rom_banks);
-
-        println!(
-            "Cartridge info {{ title: {}, type: {}, rom_size: {} B, sram_size: {} B }}",
-            title,
-            match mbc {
-                Mbc::NoMbc => "No MBC",
-                Mbc::Mbc1 { .. } => "MBC1",
+        let mbc = Mbc::new(header.cartridge_type[0], rom_banks, is_multicart(&rom))
+            .ok_or(CartridgeError::UnknownMbc(header.cartridge_type[0]))?;
+        let sram_size = match mbc.builtin_sram_size() {
+            Some(sram_size) => sram_size,
+            None => match header.sram_size() {
+                Ok(sram_size) => sram_size,
+                Err(e) => {
+                    warn_or(e)?;
+                    0
+                }
             },
-            rom_size,
-            sram_size,
-        );
-
-        assert!(
-            rom.len() == rom_size,
-            "Expected {} bytes of cartridge ROM, got {}",
-            rom_size,
-            rom.len()
+        };
+        let battery = matches!(
+            header.cartridge_type[0],
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
         );
 
-        Self {
+        let ret = Self {
+            title: header.title(),
+            checksum: global_checksum(&rom),
             rom,
             sram: vec![0; sram_size].into(),
             mbc,
+            battery,
+            sram_dirty: false,
+        };
+        Ok((ret, warnings))
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    // Global checksum computed over the ROM, which may differ from the one in the header
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.mbc.rtc().is_some()
+    }
+
+    // Whether a rumble cartridge is driving its motor
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    // Contents of the battery-backed RAM as stored in a .sav file, followed by the RTC state if any
+    pub fn save_state(&self, w: &mut Writer) {
+        self.mbc.save_state(w);
+        w.bytes(&self.sram);
+    }
+
+    // SRAM restored from a state is flushed to the .sav file like any write by the game
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.mbc.load_state(r)?;
+        self.sram.copy_from_slice(r.bytes(self.sram.len())?);
+        self.sram_dirty = true;
+        Ok(())
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut ret = self.sram.to_vec();
+        if let Some(rtc) = self.mbc.rtc() {
+            ret.extend(rtc.save());
+        }
+        ret
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.sram.len());
+        self.sram[..len].copy_from_slice(&data[..len]);
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.load(&data[len..]);
+        }
+    }
+
+    // Returns whether SRAM has been written since the last call
+    pub fn take_sram_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.sram_dirty)
+    }
+
+    pub fn emulate_cycle(&mut self) {
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.emulate_cycle();
+        }
+    }
+
+    fn sram_idx(&self, addr: u16) -> Option<usize> {
+        if self.sram.is_empty() || !self.mbc.sram_enabled() || !self.mbc.sram_selected() {
+            return None;
+        }
+        Some(self.mbc.get_addr(addr) & (self.sram.len() - 1))
+    }
+
+    // ROM or SRAM bank mapped at a cartridge address
+    pub fn bank(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x7FFF => Some((self.mbc.get_addr(addr) & (self.rom.len() - 1)) >> 14),
+            0xA000..=0xBFFF if !self.sram.is_empty() && self.mbc.sram_selected() => {
+                Some((self.mbc.get_addr(addr) & (self.sram.len() - 1)) >> 13)
+            }
+            _ => None,
         }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x7FFF => self.rom[self.mbc.get_addr(addr) & (self.rom.len() - 1)],
-            0xA000..=0xBFFF => match self.mbc {
-                Mbc::NoMbc => self.sram[addr as usize & (self.sram.len() - 1)],
-                Mbc::Mbc1 {
-                    ref sram_enable, ..
-                } => {
-                    if *sram_enable {
-                        self.sram[self.mbc.get_addr(addr) & (self.sram.len() - 1)]
-                    } else {
-                        0xFF
-                    }
+            0xA000..=0xBFFF => {
+                if let Some(reg) = self.mbc.rtc_select() {
+                    return match self.mbc.rtc() {
+                        Some(rtc) if self.mbc.sram_enabled() => rtc.read(reg),
+                        _ => 0xFF,
+                    };
                 }
-            },
+                match self.sram_idx(addr) {
+                    // only the lower nibble of MBC2 RAM exists
+                    Some(idx) if matches!(self.mbc, Mbc::Mbc2 { .. }) => self.sram[idx] | 0xF0,
+                    Some(idx) => self.sram[idx],
+                    None => 0xFF,
+                }
+            }
             _ => unreachable!(),
         }
     }
@@ -120,17 +410,114 @@ impl Cartridge {
     pub fn write(&mut self, addr: u16, val: u8) {
         match addr {
             0x0000..=0x7FFF => self.mbc.write(addr, val),
-            0xA000..=0xBFFF => match self.mbc {
-                Mbc::NoMbc => self.sram[addr as usize & (self.sram.len() - 1)] = val,
-                Mbc::Mbc1 {
-                    ref sram_enable, ..
-                } => {
-                    if *sram_enable {
-                        self.sram[self.mbc.get_addr(addr) & (self.sram.len() - 1)] = val;
+            0xA000..=0xBFFF => {
+                if let Some(reg) = self.mbc.rtc_select() {
+                    if self.mbc.sram_enabled()
+                        && let Some(rtc) = self.mbc.rtc_mut()
+                    {
+                        rtc.write(reg, val);
+                        self.sram_dirty = true;
                     }
+                    return;
                 }
-            },
+                if let Some(idx) = self.sram_idx(addr) {
+                    self.sram[idx] = if matches!(self.mbc, Mbc::Mbc2 { .. }) {
+                        val & 0x0F
+                    } else {
+                        val
+                    };
+                    self.sram_dirty = true;
+                }
+            }
             _ => unreachable!(),
         }
     }
 }
+
+impl fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{ title: {}, type: {}, rom_size: {} B, sram_size: {} B, battery: {} }}",
+            self.title,
+            self.mbc.name(),
+            self.rom.len(),
+            self.sram.len(),
+            self.battery,
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // An empty 32 KiB ROM with a valid header
+    pub fn rom(cartridge_type: u8, sram_size_code: u8) -> Box<[u8]> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cartridge_type;
+        rom[0x149] = sram_size_code;
+        rom[0x14D] = CartridgeHeader::parse(&rom).unwrap().checksum();
+        rom.into()
+    }
+
+    // MBC3 with 32 KiB of SRAM and SRAM enabled
+    fn mbc3(cartridge_type: u8) -> Cartridge {
+        let mut cartridge = Cartridge::from_bytes(rom(cartridge_type, 0x03)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge
+    }
+
+    #[test]
+    fn mbc3_without_timer_leaves_rtc_selects_unmapped() {
+        let mut cartridge = mbc3(0x13);
+        cartridge.write(0xA000, 0x42);
+        cartridge.write(0x4000, 0x08);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+        assert_eq!(cartridge.bank(0xA000), None);
+        cartridge.write(0xA000, 0x99);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc3_leaves_other_bank_selects_unmapped() {
+        let mut cartridge = mbc3(0x13);
+        cartridge.write(0xA000, 0x42);
+        for select in [0x04, 0x07, 0x0D, 0xFF] {
+            cartridge.write(0x4000, select);
+            assert_eq!(cartridge.read(0xA000), 0xFF);
+            assert_eq!(cartridge.bank(0xA000), None);
+            cartridge.write(0xA000, 0x99);
+        }
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc3_rtc_registers_replace_sram() {
+        let mut cartridge = mbc3(0x10);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xA000, 30);
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 30);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+    }
+
+    #[test]
+    fn save_data_ends_with_the_rtc() {
+        let mut cartridge = mbc3(0x10);
+        cartridge.write(0xA000, 0x42);
+        cartridge.write(0x4000, 0x09);
+        cartridge.write(0xA000, 12);
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), 0x8000 + 48);
+        let mut loaded = mbc3(0x10);
+        loaded.load_save_data(&data);
+        assert_eq!(loaded.read(0xA000), 0x42);
+        loaded.write(0x4000, 0x09);
+        assert_eq!(loaded.read(0xA000), 12);
+    }
+}
@@ -3,24 +3,52 @@ pub mod interrupts;
 mod operand;
 mod registers;
 
-use self::instructions::{go, step};
+use self::instructions::{INSTRUCTION, LEVELS, MAX_STEP, go, step};
 use self::interrupts::{Interrupts, JOYPAD, SERIAL, STAT, TIMER, VBLANK};
 use self::operand::{Cond, Direct8, Direct16, Imm8, Imm16, Indirect, Reg8, Reg16};
-use self::registers::Registers;
+pub use self::registers::Registers;
 use super::peripherals::Peripherals;
-use ::std::sync::atomic::{AtomicU8, AtomicU16, Ordering::Relaxed};
+use super::state::{Reader, StateError, Writer};
+use ::std::fmt;
 
 #[derive(Default)]
 struct Ctx {
     opcode: u8,
     cb: bool,
     int: bool,
+    // set when the last cycle finished an instruction
+    fetched: bool,
+    // address of the in-flight instruction
+    pc: u16,
+    // progress and intermediate values of the in-flight instruction per nesting level
+    step: [u8; LEVELS],
+    val8: [u8; LEVELS],
+    val16: [u16; LEVELS],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Lockup {
+    pub opcode: u8,
+    pub pc: u16,
+}
+
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "illegal opcode {:02X} at {:04X} locked up the CPU",
+            self.opcode, self.pc
+        )
+    }
 }
 
 pub struct Cpu {
     regs: Registers,
     pub interrupts: Interrupts,
     ctx: Ctx,
+    halted: bool,
+    stopped: bool,
+    locked: bool,
 }
 
 impl Cpu {
@@ -29,10 +57,163 @@ impl Cpu {
             regs: Registers::default(),
             interrupts: Interrupts::default(),
             ctx: Ctx::default(),
+            halted: false,
+            stopped: false,
+            locked: false,
+        }
+    }
+
+    // Registers as the DMG boot ROM leaves them. H and C depend on the header checksum.
+    pub fn skip_bootrom(&mut self, header_checksum: u8) {
+        self.regs
+            .write_af(if header_checksum == 0 { 0x0180 } else { 0x01B0 });
+        self.regs.write_bc(0x0013);
+        self.regs.write_de(0x00D8);
+        self.regs.write_hl(0x014D);
+        self.regs.sp = 0xFFFE;
+        self.regs.pc = 0x0100;
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        let regs = &self.regs;
+        for val in [
+            regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l,
+        ] {
+            w.u8(val);
+        }
+        w.u16(regs.sp);
+        w.u16(regs.pc);
+        w.bool(self.interrupts.ime);
+        w.u8(self.interrupts.int_flags);
+        w.u8(self.interrupts.int_enable);
+        w.bool(self.halted);
+        w.bool(self.stopped);
+        w.bool(self.locked);
+        let ctx = &self.ctx;
+        w.u8(ctx.opcode);
+        w.bool(ctx.cb);
+        w.bool(ctx.int);
+        w.bool(ctx.fetched);
+        w.u16(ctx.pc);
+        for level in 0..LEVELS {
+            w.u8(ctx.step[level]);
+            w.u8(ctx.val8[level]);
+            w.u16(ctx.val16[level]);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let regs = &mut self.regs;
+        for val in [
+            &mut regs.a,
+            &mut regs.f,
+            &mut regs.b,
+            &mut regs.c,
+            &mut regs.d,
+            &mut regs.e,
+            &mut regs.h,
+            &mut regs.l,
+        ] {
+            *val = r.u8()?;
+        }
+        regs.f &= 0xF0;
+        regs.sp = r.u16()?;
+        regs.pc = r.u16()?;
+        self.interrupts.ime = r.bool()?;
+        self.interrupts.int_flags = r.u8()?;
+        self.interrupts.int_enable = r.u8()?;
+        self.halted = r.bool()?;
+        self.stopped = r.bool()?;
+        self.locked = r.bool()?;
+        let ctx = &mut self.ctx;
+        ctx.opcode = r.u8()?;
+        ctx.cb = r.bool()?;
+        ctx.int = r.bool()?;
+        ctx.fetched = r.bool()?;
+        ctx.pc = r.u16()?;
+        for (level, max_step) in MAX_STEP.into_iter().enumerate() {
+            ctx.step[level] = r.u8()?;
+            if ctx.step[level] > max_step {
+                return Err(StateError::Invalid("CPU step"));
+            }
+            ctx.val8[level] = r.u8()?;
+            ctx.val16[level] = r.u16()?;
+        }
+        Ok(())
+    }
+
+    // Whether the next cycle starts a new instruction, or an interrupt dispatch
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.ctx.fetched
+    }
+
+    // Address of the instruction being executed, or of the next one at a boundary
+    pub fn instruction_pc(&self) -> u16 {
+        self.ctx.pc
+    }
+
+    // Registers as seen between instructions. Only meaningful at an instruction boundary,
+    // where PC has already moved past the prefetched opcode.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.ctx.pc,
+            ..self.regs
+        }
+    }
+
+    pub fn set_registers(&mut self, bus: &Peripherals, regs: Registers) {
+        let pc = self.regs.pc;
+        let jump = regs.pc != self.ctx.pc;
+        self.regs = regs;
+        self.regs.write_af(regs.af());
+        if jump {
+            self.fetch(bus);
+        } else {
+            self.regs.pc = pc;
         }
     }
 
+    // Halted until an interrupt is pending, which is then dispatched if IME is set
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Stopped until a button is pressed
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Hung by an illegal opcode, the opcode and its address are left in ctx
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.locked.then_some(Lockup {
+            opcode: self.ctx.opcode,
+            pc: self.ctx.pc,
+        })
+    }
+
     pub fn emulate_cycle(&mut self, bus: &mut Peripherals) {
+        self.ctx.fetched = false;
+        // only a reset gets the CPU going again
+        if self.locked {
+            return;
+        }
+        if self.stopped {
+            if !bus.joypad.is_any_line_low() {
+                return;
+            }
+            self.stopped = false;
+            self.fetch(bus);
+            return;
+        }
+        if self.halted {
+            // the bus is left alone while waiting, IE and IF are internal to the CPU
+            if self.interrupts.get_interrupts() == 0 {
+                return;
+            }
+            self.halted = false;
+            self.fetch(bus);
+            return;
+        }
         if self.ctx.int {
             self.call_isr(bus);
             return;
@@ -42,7 +223,8 @@ impl Cpu {
 
     // todo: もっとわかりやすく
     pub fn fetch(&mut self, bus: &Peripherals) {
-        self.ctx.opcode = bus.read(&self.interrupts, self.regs.pc); // 割り込み時もreadする必要がある？
+        self.ctx.opcode = bus.fetch(&self.interrupts, self.regs.pc); // 割り込み時もreadする必要がある？
+        self.ctx.pc = self.regs.pc;
         if self.interrupts.ime && self.interrupts.get_interrupts() > 0 {
             self.ctx.int = true;
         } else {
@@ -50,6 +232,7 @@ impl Cpu {
             self.ctx.int = false;
         }
         self.ctx.cb = false;
+        self.ctx.fetched = true;
     }
 
     pub fn decode(&mut self, bus: &mut Peripherals) {
@@ -282,7 +465,7 @@ impl Cpu {
             0xd0 => self.retc(bus, Cond::NC),
             0xd1 => self.pop(bus, Reg16::DE),
             0xd2 => self.jpc(bus, Cond::NC),
-            // 0xd3
+            0xd3 => self.lock(),
             0xd4 => self.callc(bus, Cond::NC),
             0xd5 => self.push(bus, Reg16::DE),
             0xd6 => self.sub(bus, Imm8),
@@ -290,26 +473,26 @@ impl Cpu {
             0xd8 => self.retc(bus, Cond::C),
             0xd9 => self.reti(bus),
             0xda => self.jpc(bus, Cond::C),
-            // 0xdb
+            0xdb => self.lock(),
             0xdc => self.callc(bus, Cond::C),
-            // 0xdd
+            0xdd => self.lock(),
             0xde => self.sbc(bus, Imm8),
             0xdf => self.rst(bus, 0x18),
 
             0xe0 => self.ld(bus, Direct8::DFF, Reg8::A),
             0xe1 => self.pop(bus, Reg16::HL),
             0xe2 => self.ld(bus, Indirect::CFF, Reg8::A),
-            // 0xe3
-            // 0xe4
+            0xe3 => self.lock(),
+            0xe4 => self.lock(),
             0xe5 => self.push(bus, Reg16::HL),
             0xe6 => self.and(bus, Imm8),
             0xe7 => self.rst(bus, 0x20),
             0xe8 => self.addsp(bus),
             0xe9 => self.jphl(bus),
             0xea => self.ld(bus, Direct8::D, Reg8::A),
-            // 0xeb
-            // 0xec
-            // 0xed
+            0xeb => self.lock(),
+            0xec => self.lock(),
+            0xed => self.lock(),
             0xee => self.xor(bus, Imm8),
             0xef => self.rst(bus, 0x28),
 
@@ -317,7 +500,7 @@ impl Cpu {
             0xf1 => self.pop(bus, Reg16::AF),
             0xf2 => self.ld(bus, Reg8::A, Indirect::CFF),
             0xf3 => self.di(bus),
-            // 0xf4
+            0xf4 => self.lock(),
             0xf5 => self.push(bus, Reg16::AF),
             0xf6 => self.or(bus, Imm8),
             0xf7 => self.rst(bus, 0x30),
@@ -325,11 +508,10 @@ impl Cpu {
             0xf9 => self.ldsphl(bus),
             0xfa => self.ld(bus, Reg8::A, Direct8::D),
             0xfb => self.ei(bus),
-            // 0xfc
-            // 0xfd
+            0xfc => self.lock(),
+            0xfd => self.lock(),
             0xfe => self.cp(bus, Imm8),
             0xff => self.rst(bus, 0x38),
-            _ => panic!("Not implemented: {:02x}", self.ctx.opcode),
         }
     }
 
@@ -610,7 +792,7 @@ impl Cpu {
     }
 
     fn call_isr(&mut self, bus: &mut Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(_) = self.push16(bus, self.regs.pc) {
                 let highest_int: u8 = 1 << self.interrupts.get_interrupts().trailing_zeros();
                 self.interrupts.int_flags &= !highest_int;
@@ -622,11 +804,11 @@ impl Cpu {
                     JOYPAD => 0x0060,
                     _ => panic!("Invalid interrupt: {:02x}", highest_int),
                 };
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
                 self.interrupts.ime = false;
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus)
             },
         });
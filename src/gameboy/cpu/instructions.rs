@@ -3,23 +3,29 @@ use super::{
     Cpu,
     operand::{Cond, IO8, IO16, Imm8, Imm16, Reg16},
 };
-use ::std::sync::atomic::{AtomicU8, AtomicU16, Ordering::Relaxed};
+
+// Nesting levels of the in-flight state kept in `Ctx`.
+// An instruction can be in the middle of an operand access,
+// which can itself be in the middle of reading an immediate value.
+pub const INSTRUCTION: usize = 0;
+pub const OPERAND: usize = 1;
+pub const IMMEDIATE: usize = 2;
+pub const LEVELS: usize = 3;
+// Highest step the machines of each level go to, checked when loading a save state
+pub const MAX_STEP: [u8; LEVELS] = [2, 4, 1];
 
 macro_rules! step {
-    ($d:expr, {$($c:tt : $e:expr,)*}) => {
-        static STEP: AtomicU8 = AtomicU8::new(0);
-        #[allow(dead_code)]
-        static VAL8: AtomicU8 = AtomicU8::new(0);
+    ($s:ident, $l:expr, $d:expr, {$($c:tt : $e:expr,)*}) => {
         #[allow(dead_code)]
-        static VAL16: AtomicU16 = AtomicU16::new(0);
-        $(if STEP.load(Relaxed) == $c {$e})* else { return $d; }
+        const LEVEL: usize = $l;
+        $(if $s.ctx.step[LEVEL] == $c {$e})* else { return $d; }
     };
 }
 pub(crate) use step;
 
 macro_rules! go {
-    ($e:expr) => {
-        STEP.store($e, Relaxed)
+    ($s:ident, $e:expr) => {
+        $s.ctx.step[LEVEL] = $e
     };
 }
 pub(crate) use go;
@@ -41,16 +47,16 @@ impl Cpu {
     where
         Self: IO8<D> + IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
-                VAL8.store(v, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = v;
+                go!(self, 1);
             },
-            1: if self.write8(bus, dst, VAL8.load(Relaxed)).is_some() {
-                go!(2);
+            1: if self.write8(bus, dst, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -60,16 +66,16 @@ impl Cpu {
     where
         Self: IO16<D> + IO16<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, src) {
-                VAL16.store(v, Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = v;
+                go!(self, 1);
             },
-            1: if self.write16(bus, dst, VAL16.load(Relaxed)).is_some(){
-                go!(2);
+            1: if self.write16(bus, dst, self.ctx.val16[LEVEL]).is_some(){
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -93,17 +99,17 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v.wrapping_add(1);
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(v & 0xf == 0xf);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -113,16 +119,16 @@ impl Cpu {
     where
         Self: IO16<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, src) {
-                VAL16.store(v.wrapping_add(1), Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = v.wrapping_add(1);
+                go!(self, 1);
             },
-            1: if self.write16(bus, src, VAL16.load(Relaxed)).is_some() {
-                return go!(2);
+            1: if self.write16(bus, src, self.ctx.val16[LEVEL]).is_some() {
+                return go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -132,17 +138,17 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v.wrapping_sub(1);
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(true);
                 self.regs.set_hf(v & 0xf == 0);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -152,16 +158,16 @@ impl Cpu {
     where
         Self: IO16<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, src) {
-                VAL16.store(v.wrapping_sub(1), Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = v.wrapping_sub(1);
+                go!(self, 1);
             },
-            1: if self.write16(bus, src, VAL16.load(Relaxed)).is_some() {
-                return go!(2);
+            1: if self.write16(bus, src, self.ctx.val16[LEVEL]).is_some() {
+                return go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -171,18 +177,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = (v << 1) | self.regs.cf() as u8;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v & 0x80 > 0);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -202,63 +208,63 @@ impl Cpu {
     }
 
     pub fn push16(&mut self, bus: &mut Peripherals, val: u16) -> Option<()> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: {
-                go!(1);
+                go!(self, 1);
                 return None;
             },
             1 : {
                 let [lo, hi] = u16::to_le_bytes(val);
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
                 bus.write(&mut self.interrupts, self.regs.sp, hi);
-                VAL8.store(lo, Relaxed);
-                go!(2);
+                self.ctx.val8[LEVEL] = lo;
+                go!(self, 2);
                 return None;
             },
             2: {
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
-                bus.write(&mut self.interrupts, self.regs.sp, VAL8.load(Relaxed));
-                go!(3);
+                bus.write(&mut self.interrupts, self.regs.sp, self.ctx.val8[LEVEL]);
+                go!(self, 3);
                 return None;
             },
-            3: return Some(go!(0)),
+            3: return Some(go!(self, 0)),
         });
     }
 
     pub fn push(&mut self, bus: &mut Peripherals, src: Reg16) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: {
-                VAL16.store(self.read16(bus, src).unwrap(), Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = self.read16(bus, src).unwrap();
+                go!(self, 1);
             },
-            1: if self.push16(bus, VAL16.load(Relaxed)).is_some() {
-                go!(2);
+            1: if self.push16(bus, self.ctx.val16[LEVEL]).is_some() {
+                go!(self, 2);
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn pop16(&mut self, bus: &Peripherals) -> Option<u16> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: {
-                VAL8.store(bus.read(&self.interrupts, self.regs.sp), Relaxed);
+                self.ctx.val8[LEVEL] = bus.read(&self.interrupts, self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                go!(1);
+                go!(self, 1);
                 return None;
             },
             1: {
                 let hi = bus.read(&self.interrupts, self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.ctx.val16[LEVEL] = u16::from_le_bytes([self.ctx.val8[LEVEL], hi]);
+                go!(self, 2);
                 return None;
             },
             2: {
-                go!(0);
-                return Some(VAL16.load(Relaxed));
+                go!(self, 0);
+                return Some(self.ctx.val16[LEVEL]);
             },
         });
     }
@@ -271,13 +277,13 @@ impl Cpu {
     }
 
     pub fn jr(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, Imm8){
                 self.regs.pc = self.regs.pc.wrapping_add(v as i8 as u16);
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -293,57 +299,57 @@ impl Cpu {
     }
 
     pub fn jr_c(&mut self, bus: &Peripherals, c: Cond) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, Imm8) {
-                go!(1);
+                go!(self, 1);
                 if self.cond(c) {
                     self.regs.pc = self.regs.pc.wrapping_add(v as i8 as u16);
                     return;
                 }
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn call(&mut self, bus: &mut Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, Imm16) {
-                VAL16.store(v, Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = v;
+                go!(self, 1);
             },
             1: if self.push16(bus, self.regs.pc).is_some() {
-                self.regs.pc = VAL16.load(Relaxed);
-                go!(0);
+                self.regs.pc = self.ctx.val16[LEVEL];
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn ret(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.pop16(bus) {
                 self.regs.pc = v;
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn reti(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.pop16(bus) {
                 self.regs.pc = v;
-                return go!(1);
+                return go!(self, 1);
             },
             1: {
                 self.interrupts.ime = true;
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -359,9 +365,17 @@ impl Cpu {
         self.fetch(bus);
     }
 
+    // With an interrupt already pending HALT does not halt. Without IME that is the halt bug:
+    // PC fails to increment past the next opcode, which is executed twice.
     pub fn halt(&mut self, bus: &Peripherals) {
-        if self.interrupts.get_interrupts() > 0 {
-            self.fetch(bus);
+        if self.interrupts.get_interrupts() == 0 {
+            self.halted = true;
+            return;
+        }
+        let ime = self.interrupts.ime;
+        self.fetch(bus);
+        if !ime {
+            self.regs.pc = self.regs.pc.wrapping_sub(1);
         }
     }
 
@@ -369,7 +383,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let (result, carry) = self.regs.a.overflowing_add(v);
                 self.regs.set_zf(result == 0);
@@ -386,7 +400,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let cf = self.regs.cf() as u8;
                 let result = self.regs.a.wrapping_add(v).wrapping_add(cf);
@@ -404,7 +418,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let (result, carry) = self.regs.a.overflowing_sub(v);
                 self.regs.set_zf(result == 0);
@@ -421,7 +435,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let cf = self.regs.cf() as u8;
                 let result = self.regs.a.wrapping_sub(v).wrapping_sub(cf);
@@ -439,7 +453,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 self.regs.a = self.regs.a & v;
                 self.regs.set_zf(self.regs.a == 0);
@@ -455,7 +469,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 self.regs.a = self.regs.a | v;
                 self.regs.set_zf(self.regs.a == 0);
@@ -471,7 +485,7 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 self.regs.a = self.regs.a ^ v;
                 self.regs.set_zf(self.regs.a == 0);
@@ -525,19 +539,19 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
-                VAL8.store(v, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = v;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed).rotate_left(1)).is_some() {
-                let val = VAL8.load(Relaxed);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL].rotate_left(1)).is_some() {
+                let val = self.ctx.val8[LEVEL];
                 let result = val.rotate_left(1);
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(val >> 7 == 1);
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -547,19 +561,19 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
-                VAL8.store(v, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = v;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed).rotate_right(1)).is_some() {
-                let val = VAL8.load(Relaxed);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL].rotate_right(1)).is_some() {
+                let val = self.ctx.val8[LEVEL];
                 let result = val.rotate_right(1);
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(val & 0x01 == 1);
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -569,18 +583,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v >> 1 | (self.regs.cf() as u8) << 7;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v & 0x01 == 1);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -590,18 +604,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v << 1;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v >> 7 == 1);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -611,18 +625,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v & 0x80 | v >> 1;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v & 0x01 == 1);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -632,18 +646,18 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v >> 1;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(v & 0x01 == 1);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -653,14 +667,14 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v | (1 << num);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -670,28 +684,28 @@ impl Cpu {
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v & !(1 << num);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn jp(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
            0: if let Some(v) = self.read16(bus, Imm16) {
                self.regs.pc = v;
-               go!(1);
+               go!(self, 1);
                return;
            },
            1: {
-               go!(0);
+               go!(self, 0);
                self.fetch(bus);
            },
         });
@@ -703,58 +717,58 @@ impl Cpu {
     }
 
     pub fn jpc(&mut self, bus: &Peripherals, c: Cond) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
            0: if let Some(v) = self.read16(bus, Imm16) {
                if !self.cond(c) {
                    self.fetch(bus);
                    return;
                }
                self.regs.pc = v;
-               go!(1);
+               go!(self, 1);
                return;
            },
            1: {
-               go!(0);
+               go!(self, 0);
                self.fetch(bus);
            },
         });
     }
 
     pub fn callc(&mut self, bus: &mut Peripherals, c: Cond) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, Imm16) {
                 if !self.cond(c) {
                     self.fetch(bus);
                     return;
                 }
-                VAL16.store(v, Relaxed);
-                go!(1);
+                self.ctx.val16[LEVEL] = v;
+                go!(self, 1);
             },
             1: if self.push16(bus, self.regs.pc).is_some() {
-                self.regs.pc = VAL16.load(Relaxed);
-                go!(0);
+                self.regs.pc = self.ctx.val16[LEVEL];
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn retc(&mut self, bus: &Peripherals, c: Cond) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: {
                 if self.cond(c) {
-                    go!(1);
+                    go!(self, 1);
                 } else {
-                    go!(2);
+                    go!(self, 2);
                 }
                 return;
             },
             1: if let Some(v) = self.pop16(bus) {
                 self.regs.pc = v;
-                go!(2);
+                go!(self, 2);
                 return;
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -767,27 +781,36 @@ impl Cpu {
         }
     }
 
-    pub fn stop(&mut self, _: &Peripherals) {
-        // 実装を省略
-        panic!("stop called");
+    // Illegal opcodes hang the CPU for good, interrupts included
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    // STOP skips the byte that follows it and stops the system clock, resetting DIV, until a
+    // button is pressed. The CGB speed switch through KEY1 does not exist on DMG.
+    pub fn stop(&mut self, bus: &mut Peripherals) {
+        if self.read8(bus, Imm8).is_some() {
+            bus.timer.set_div(0);
+            self.stopped = true;
+        }
     }
 
     pub fn swap<S: Copy>(&mut self, bus: &mut Peripherals, src: S)
     where
         Self: IO8<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, src) {
                 let result = v >> 4 | v << 4;
                 self.regs.set_zf(result == 0);
                 self.regs.set_nf(false);
                 self.regs.set_hf(false);
                 self.regs.set_cf(false);
-                VAL8.store(result, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = result;
+                go!(self, 1);
             },
-            1: if self.write8(bus, src, VAL8.load(Relaxed)).is_some() {
-                go!(0);
+            1: if self.write8(bus, src, self.ctx.val8[LEVEL]).is_some() {
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -836,32 +859,32 @@ impl Cpu {
     }
 
     pub fn ldsphl(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: {
                 self.regs.sp = self.regs.hl();
-                go!(1);
+                go!(self, 1);
                 return;
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn ldhlsp(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, Imm8) {
                 self.regs.write_hl(self.regs.sp.wrapping_add(v as i8 as u16));
                 self.regs.set_zf(false);
                 self.regs.set_nf(false);
                 self.regs.set_hf((self.regs.sp & 0x0f) + (v as i8 as u16 & 0x0f) > 0x0f);
                 self.regs.set_cf((self.regs.sp & 0xff) + (v as i8 as u16 & 0xff) > 0xff);
-                go!(1);
+                go!(self, 1);
                 return;
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
@@ -871,40 +894,40 @@ impl Cpu {
     where
         Self: IO16<S>,
     {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read16(bus, src) {
                 let (result, carry) = self.regs.hl().overflowing_add(v);
                 self.regs.set_nf(false);
                 self.regs.set_hf((self.regs.hl() & 0xfff) + (v & 0xfff) > 0xfff);
                 self.regs.set_cf(carry);
                 self.regs.write_hl(result);
-                go!(1);
+                go!(self, 1);
                 return;
             },
             1: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
     }
 
     pub fn addsp(&mut self, bus: &Peripherals) {
-        step!((), {
+        step!(self, INSTRUCTION, (), {
             0: if let Some(v) = self.read8(bus, Imm8) {
                 self.regs.set_zf(false);
                 self.regs.set_nf(false);
                 self.regs.set_hf((self.regs.sp & 0x0f) + (v as i8 as u16 & 0x0f) > 0x0f);
                 self.regs.set_cf((self.regs.sp & 0xff) + (v as i8 as u16 & 0xff) > 0xff);
                 self.regs.sp = self.regs.sp.wrapping_add(v as i8 as u16);
-                go!(1);
+                go!(self, 1);
                 return;
             },
             1: {
-                go!(2);
+                go!(self, 2);
                 return;
             },
             2: {
-                go!(0);
+                go!(self, 0);
                 self.fetch(bus);
             },
         });
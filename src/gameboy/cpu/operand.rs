@@ -1,9 +1,8 @@
 use super::{
     super::peripherals::Peripherals,
     Cpu,
-    instructions::{go, step},
+    instructions::{IMMEDIATE, OPERAND, go, step},
 };
-use ::std::sync::atomic::{AtomicU8, AtomicU16, Ordering::Relaxed};
 
 pub trait IO8<T: Copy> {
     fn read8(&mut self, bus: &Peripherals, src: T) -> Option<u8>;
@@ -126,16 +125,16 @@ impl IO16<Reg16> for Cpu {
 
 impl IO8<Imm8> for Cpu {
     fn read8(&mut self, bus: &Peripherals, _: Imm8) -> Option<u8> {
-        step!(None, {
+        step!(self, IMMEDIATE, None, {
             0: {
-                VAL8.store(bus.read(&self.interrupts, self.regs.pc), Relaxed);
+                self.ctx.val8[LEVEL] = bus.read(&self.interrupts, self.regs.pc);
                 self.regs.pc = self.regs.pc.wrapping_add(1);
-                go!(1);
+                go!(self, 1);
                 return None;
             },
             1: {
-                go!(0);
-                return Some(VAL8.load(Relaxed));
+                go!(self, 0);
+                return Some(self.ctx.val8[LEVEL]);
             },
         });
     }
@@ -147,18 +146,18 @@ impl IO8<Imm8> for Cpu {
 
 impl IO16<Imm16> for Cpu {
     fn read16(&mut self, bus: &Peripherals, _: Imm16) -> Option<u16> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                VAL8.store(lo, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = lo;
+                go!(self, 1);
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.ctx.val16[LEVEL] = u16::from_le_bytes([self.ctx.val8[LEVEL], hi]);
+                go!(self, 2);
             },
             2: {
-                go!(0);
-                return Some(VAL16.load(Relaxed));
+                go!(self, 0);
+                return Some(self.ctx.val16[LEVEL]);
             },
         });
     }
@@ -170,10 +169,9 @@ impl IO16<Imm16> for Cpu {
 
 impl IO8<Indirect> for Cpu {
     fn read8(&mut self, bus: &Peripherals, src: Indirect) -> Option<u8> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: {
-                VAL8.store(
-                    match src {
+                self.ctx.val8[LEVEL] = match src {
                         Indirect::BC => bus.read(&self.interrupts, self.regs.bc()),
                         Indirect::DE => bus.read(&self.interrupts, self.regs.de()),
                         Indirect::HL => bus.read(&self.interrupts, self.regs.hl()),
@@ -188,19 +186,19 @@ impl IO8<Indirect> for Cpu {
                             self.regs.write_hl(addr.wrapping_add(1));
                             bus.read(&self.interrupts, addr)
                         },
-                    }, Relaxed);
-                go!(1);
+                    };
+                go!(self, 1);
                 return None;
             },
             1: {
-                go!(0);
-                return Some(VAL8.load(Relaxed));
+                go!(self, 0);
+                return Some(self.ctx.val8[LEVEL]);
             },
         });
     }
 
     fn write8(&mut self, bus: &mut Peripherals, dst: Indirect, val: u8) -> Option<()> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: {
                 match dst {
                     Indirect::BC => bus.write(&mut self.interrupts, self.regs.bc(), val),
@@ -218,61 +216,61 @@ impl IO8<Indirect> for Cpu {
                         bus.write(&mut self.interrupts, addr, val);
                     },
                 }
-                go!(1);
+                go!(self, 1);
                 return None;
             },
-            1: return Some(go!(0)),
+            1: return Some(go!(self, 0)),
         });
     }
 }
 
 impl IO8<Direct8> for Cpu {
     fn read8(&mut self, bus: &Peripherals, src: Direct8) -> Option<u8> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                VAL8.store(lo, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = lo;
+                go!(self, 1);
                 if let Direct8::DFF = src {
-                    VAL16.store(0xFF00 | (lo as u16), Relaxed);
-                    go!(2);
+                    self.ctx.val16[LEVEL] = 0xFF00 | (lo as u16);
+                    go!(self, 2);
                 }
             },
             1: if let Some(hi) = self.read8(bus, Imm8){
-                VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.ctx.val16[LEVEL] = u16::from_le_bytes([self.ctx.val8[LEVEL], hi]);
+                go!(self, 2);
             },
             2: {
-                VAL8.store(bus.read(&self.interrupts, VAL16.load(Relaxed)), Relaxed);
-                go!(3);
+                self.ctx.val8[LEVEL] = bus.read(&self.interrupts, self.ctx.val16[LEVEL]);
+                go!(self, 3);
                 return None;
             },
             3: {
-                go!(0);
-                return Some(VAL8.load(Relaxed));
+                go!(self, 0);
+                return Some(self.ctx.val8[LEVEL]);
             },
         });
     }
 
     fn write8(&mut self, bus: &mut Peripherals, dst: Direct8, val: u8) -> Option<()> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                VAL8.store(lo, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = lo;
+                go!(self, 1);
                 if let Direct8::DFF = dst {
-                    VAL16.store(0xFF00 | (lo as u16), Relaxed);
-                    go!(2);
+                    self.ctx.val16[LEVEL] = 0xFF00 | (lo as u16);
+                    go!(self, 2);
                 }
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.ctx.val16[LEVEL] = u16::from_le_bytes([self.ctx.val8[LEVEL], hi]);
+                go!(self, 2);
             },
             2: {
-                bus.write(&mut self.interrupts, VAL16.load(Relaxed), val);
-                go!(3);
+                bus.write(&mut self.interrupts, self.ctx.val16[LEVEL], val);
+                go!(self, 3);
                 return None;
             },
-            3: return Some(go!(0)),
+            3: return Some(go!(self, 0)),
         });
     }
 }
@@ -283,26 +281,26 @@ impl IO16<Direct16> for Cpu {
     }
 
     fn write16(&mut self, bus: &mut Peripherals, _: Direct16, val: u16) -> Option<()> {
-        step!(None, {
+        step!(self, OPERAND, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                VAL8.store(lo, Relaxed);
-                go!(1);
+                self.ctx.val8[LEVEL] = lo;
+                go!(self, 1);
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(2);
+                self.ctx.val16[LEVEL] = u16::from_le_bytes([self.ctx.val8[LEVEL], hi]);
+                go!(self, 2);
             },
             2: {
-                bus.write(&mut self.interrupts, VAL16.load(Relaxed), val as u8);
-                go!(3);
+                bus.write(&mut self.interrupts, self.ctx.val16[LEVEL], val as u8);
+                go!(self, 3);
                 return None;
             },
             3: {
-                bus.write(&mut self.interrupts, VAL16.load(Relaxed).wrapping_add(1), (val >> 8) as u8);
-                go!(4);
+                bus.write(&mut self.interrupts, self.ctx.val16[LEVEL].wrapping_add(1), (val >> 8) as u8);
+                go!(self, 4);
                 return None;
             },
-            4: return Some(go!(0)),
+            4: return Some(go!(self, 0)),
         });
     }
 }
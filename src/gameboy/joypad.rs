@@ -0,0 +1,121 @@
+use super::cpu::interrupts;
+use super::cpu::interrupts::Interrupts;
+use super::state::{Reader, StateError, Writer};
+
+const SELECT_DIRECTION: u8 = 1 << 4;
+const SELECT_ACTION: u8 = 1 << 5;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // (is action button, bit on P10-P13)
+    fn line(self) -> (bool, u8) {
+        match self {
+            Self::Right => (false, 1 << 0),
+            Self::Left => (false, 1 << 1),
+            Self::Up => (false, 1 << 2),
+            Self::Down => (false, 1 << 3),
+            Self::A => (true, 1 << 0),
+            Self::B => (true, 1 << 1),
+            Self::Select => (true, 1 << 2),
+            Self::Start => (true, 1 << 3),
+        }
+    }
+}
+
+pub struct Joypad {
+    select: u8,
+    direction: u8,
+    action: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_DIRECTION | SELECT_ACTION,
+            direction: 0,
+            action: 0,
+        }
+    }
+
+    // P10-P13 pulled low by the pressed buttons of the selected groups (1 = low)
+    fn lines(&self) -> u8 {
+        let mut ret = 0;
+        if self.select & SELECT_DIRECTION == 0 {
+            ret |= self.direction;
+        }
+        if self.select & SELECT_ACTION == 0 {
+            ret |= self.action;
+        }
+        ret
+    }
+
+    // Wakes the CPU from STOP, independently of the interrupt
+    pub fn is_any_line_low(&self) -> bool {
+        self.lines() > 0
+    }
+
+    // the interrupt is requested when any of P10-P13 goes from high to low
+    fn check_interrupt(&self, interrupts: &mut Interrupts, before: u8) {
+        if self.lines() & !before > 0 {
+            interrupts.irq(interrupts::JOYPAD);
+        }
+    }
+
+    pub fn press(&mut self, interrupts: &mut Interrupts, button: Button) {
+        let before = self.lines();
+        match button.line() {
+            (true, bit) => self.action |= bit,
+            (false, bit) => self.direction |= bit,
+        }
+        self.check_interrupt(interrupts, before);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        match button.line() {
+            (true, bit) => self.action &= !bit,
+            (false, bit) => self.direction &= !bit,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u8(self.select);
+        w.u8(self.direction);
+        w.u8(self.action);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.select = r.u8()? & (SELECT_DIRECTION | SELECT_ACTION);
+        self.direction = r.u8()? & 0x0F;
+        self.action = r.u8()? & 0x0F;
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => 0b11000000 | self.select | (!self.lines() & 0x0F),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, interrupts: &mut Interrupts, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => {
+                let before = self.lines();
+                self.select = val & (SELECT_DIRECTION | SELECT_ACTION);
+                self.check_interrupt(interrupts, before);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,45 +1,184 @@
+mod apu;
 mod bootrom;
+mod dma;
 mod hram;
 pub mod mbc;
 mod ppu;
 mod wram;
 
+use self::apu::Apu;
+pub use self::apu::SAMPLE_RATE;
 pub use self::bootrom::Bootrom;
+use self::dma::Dma;
 use self::hram::HRam;
 use self::ppu::Ppu;
+pub use self::ppu::{LCD_HEIGHT, LCD_PIXELS, LCD_WIDTH};
 use self::wram::WRam;
 use super::cartridge::Cartridge;
 use super::cpu::interrupts::Interrupts;
+use super::joypad::Joypad;
+use super::state::{Reader, Sections, StateError, Writer};
 use super::timer::Timer;
-use ::sdl2::Sdl;
+use super::watchpoint::{Access, WatchHit, Watchpoints};
 
 pub struct Peripherals {
-    bootrom: Bootrom,
+    bootrom: Option<Bootrom>,
     wram: WRam,
     hram: HRam,
     pub ppu: Ppu,
+    pub apu: Apu,
+    dma: Dma,
     pub timer: Timer,
-    cartridge: Cartridge,
+    pub joypad: Joypad,
+    pub cartridge: Cartridge,
+    pub watchpoints: Watchpoints,
 }
 
 impl Peripherals {
-    pub fn new(bootrom: Bootrom, cartridge: Cartridge, sdl: &Sdl) -> Self {
+    pub fn new(bootrom: Option<Bootrom>, cartridge: Cartridge) -> Self {
         Self {
             bootrom,
             wram: WRam::new(),
             hram: HRam::new(),
-            ppu: Ppu::new(sdl),
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            dma: Dma::new(),
             timer: Timer::default(),
+            joypad: Joypad::new(),
             cartridge,
+            watchpoints: Watchpoints::default(),
             // serial: ' ',
         }
     }
 
+    fn is_bootrom_active(&self) -> bool {
+        self.bootrom.as_ref().is_some_and(|b| b.is_active())
+    }
+
+    // I/O registers as the DMG boot ROM leaves them
+    pub fn skip_bootrom(&mut self, interrupts: &mut Interrupts) {
+        const IO: [(u16, u8); 24] = [
+            (0xFF00, 0xCF),
+            (0xFF07, 0xF8),
+            (0xFF0F, 0xE1),
+            // sound is powered on first, the channels are set up without being triggered
+            (0xFF26, 0xF1),
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF13, 0xFF),
+            (0xFF14, 0x3F),
+            (0xFF16, 0x3F),
+            (0xFF18, 0xFF),
+            (0xFF19, 0x3F),
+            (0xFF1A, 0x7F),
+            (0xFF1B, 0xFF),
+            (0xFF1C, 0x9F),
+            (0xFF1D, 0xFF),
+            (0xFF1E, 0x3F),
+            (0xFF20, 0xFF),
+            (0xFF23, 0x3F),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+            (0xFF40, 0x91),
+            (0xFF47, 0xFC),
+            (0xFFFF, 0x00),
+        ];
+        for (addr, val) in IO {
+            self.write(interrupts, addr, val);
+        }
+        self.timer.set_div(0xABCC);
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.section(b"PPU ", |w| self.ppu.save_state(w));
+        w.section(b"APU ", |w| self.apu.save_state(w));
+        w.section(b"TIMR", |w| self.timer.save_state(w));
+        w.section(b"DMA ", |w| self.dma.save_state(w));
+        w.section(b"JOYP", |w| self.joypad.save_state(w));
+        w.section(b"WRAM", |w| self.wram.save_state(w));
+        w.section(b"HRAM", |w| self.hram.save_state(w));
+        w.section(b"BOOT", |w| w.bool(self.is_bootrom_active()));
+        w.section(b"CART", |w| self.cartridge.save_state(w));
+    }
+
+    pub fn load_state(&mut self, sections: &Sections) -> Result<(), StateError> {
+        sections.load(b"PPU ", |r| self.ppu.load_state(r))?;
+        sections.load(b"APU ", |r| self.apu.load_state(r))?;
+        sections.load(b"TIMR", |r| self.timer.load_state(r))?;
+        sections.load(b"DMA ", |r| self.dma.load_state(r))?;
+        sections.load(b"JOYP", |r| self.joypad.load_state(r))?;
+        sections.load(b"WRAM", |r| self.wram.load_state(r))?;
+        sections.load(b"HRAM", |r| self.hram.load_state(r))?;
+        sections.load(b"BOOT", |r| self.load_bootrom_state(r))?;
+        sections.load(b"CART", |r| self.cartridge.load_state(r))
+    }
+
+    // A state saved while the boot ROM runs needs one to continue
+    fn load_bootrom_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let active = r.bool()?;
+        match self.bootrom.as_mut() {
+            Some(bootrom) => bootrom.set_active(active),
+            None if active => {
+                return Err(StateError::Invalid("boot ROM mapping without a boot ROM"));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    // While OAM DMA is running it owns the external and video buses, the CPU can still reach
+    // the I/O registers, HRAM and IE
+    fn is_blocked_by_dma(&self, addr: u16) -> bool {
+        self.dma.is_active() && addr < 0xFF00
+    }
+
+    pub fn emulate_dma_cycle(&mut self, interrupts: &Interrupts) {
+        if let Some((src, idx)) = self.dma.emulate_cycle() {
+            let val = self.read_bus(interrupts, src);
+            self.ppu.write_oam(idx, val);
+        }
+    }
+
     pub fn read(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+        if self.is_blocked_by_dma(addr) {
+            return 0xFF;
+        }
+        let val = self.read_bus(interrupts, addr);
+        self.watch(Access::Read, addr, val);
+        val
+    }
+
+    // Reads an opcode, which is left to breakpoints rather than watchpoints
+    pub fn fetch(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+        if self.is_blocked_by_dma(addr) {
+            return 0xFF;
+        }
+        self.read_bus(interrupts, addr)
+    }
+
+    fn watch(&self, access: Access, addr: u16, value: u8) {
+        if self.watchpoints.matches(access, addr, value) {
+            self.watchpoints.record(WatchHit {
+                access,
+                addr,
+                value,
+                pc: self.watchpoints.pc,
+                bank: self.cartridge.bank(addr),
+            });
+        }
+    }
+
+    // Reads regardless of an ongoing DMA, for the frontend
+    pub fn peek(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+        self.read_bus(interrupts, addr)
+    }
+
+    fn read_bus(&self, interrupts: &Interrupts, addr: u16) -> u8 {
         match addr {
             0x0000..=0x00FF => {
-                if self.bootrom.is_active() {
-                    self.bootrom.read(addr)
+                if let Some(bootrom) = self.bootrom.as_ref().filter(|b| b.is_active()) {
+                    bootrom.read(addr)
                 } else {
                     self.cartridge.read(addr)
                 }
@@ -49,8 +188,11 @@ impl Peripherals {
             0xA000..=0xBFFF => self.cartridge.read(addr),
             0xC000..=0xFDFF => self.wram.read(addr),
             0xFE00..=0xFE9F => self.ppu.read(addr),
+            0xFF00 => self.joypad.read(addr),
             0xFF04..=0xFF07 => self.timer.read(addr),
             0xFF0F => interrupts.read(addr),
+            0xFF10..=0xFF3F => self.apu.read(addr),
+            0xFF46 => self.dma.read(addr),
             0xFF40..=0xFF4B => self.ppu.read(addr),
             0xFF80..=0xFFFE => self.hram.read(addr),
             0xFFFF => interrupts.read(addr),
@@ -59,9 +201,13 @@ impl Peripherals {
     }
 
     pub fn write(&mut self, interrupts: &mut Interrupts, addr: u16, val: u8) {
+        if self.is_blocked_by_dma(addr) {
+            return;
+        }
+        self.watch(Access::Write, addr, val);
         match addr {
             0x0000..=0x00FF => {
-                if !self.bootrom.is_active() {
+                if !self.is_bootrom_active() {
                     self.cartridge.write(addr, val)
                 }
             }
@@ -70,10 +216,17 @@ impl Peripherals {
             0xA000..=0xBFFF => self.cartridge.write(addr, val),
             0xC000..=0xFDFF => self.wram.write(addr, val),
             0xFE00..=0xFE9F => self.ppu.write(addr, val),
+            0xFF00 => self.joypad.write(interrupts, addr, val),
             0xFF04..=0xFF07 => self.timer.write(addr, val),
             0xFF0F => interrupts.write(addr, val),
+            0xFF10..=0xFF3F => self.apu.write(addr, val),
+            0xFF46 => self.dma.write(addr, val),
             0xFF40..=0xFF4B => self.ppu.write(addr, val),
-            0xFF50 => self.bootrom.write(addr, val),
+            0xFF50 => {
+                if let Some(bootrom) = self.bootrom.as_mut() {
+                    bootrom.write(addr, val)
+                }
+            }
             0xFF80..=0xFFFE => self.hram.write(addr, val),
             0xFFFF => interrupts.write(addr, val),
             _ => (),
@@ -0,0 +1,233 @@
+mod envelope;
+mod length;
+mod noise;
+mod square;
+mod wave;
+
+use self::noise::Noise;
+use self::square::Square;
+use self::wave::Wave;
+use super::super::state::{Reader, StateError, Writer};
+use super::super::{CPU_CLOCK_HZ, M_CYCLE_CLOCK};
+
+pub const SAMPLE_RATE: u32 = 48_000;
+
+const M_CYCLE_HZ: u32 = (CPU_CLOCK_HZ / M_CYCLE_CLOCK) as u32;
+// keep at most one second of stereo samples when the frontend does not consume them
+const MAX_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+// the frame sequencer is clocked by the falling edge of bit 4 of DIV
+const DIV_APU_BIT: u16 = 1 << 12;
+
+const POWER: u8 = 1 << 7;
+
+pub struct Apu {
+    power: bool,
+    nr50: u8,
+    nr51: u8,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    sequencer_step: u8,
+    div_bit: bool,
+    sample_clock: u32,
+    capacitor: [f32; 2],
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            power: false,
+            nr50: 0,
+            nr51: 0,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            sequencer_step: 0,
+            div_bit: false,
+            sample_clock: 0,
+            capacitor: [0.0; 2],
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bool(self.power);
+        w.u8(self.nr50);
+        w.u8(self.nr51);
+        self.square1.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.u8(self.sequencer_step);
+        w.bool(self.div_bit);
+        w.u32(self.sample_clock);
+        for capacitor in self.capacitor {
+            w.u32(capacitor.to_bits());
+        }
+    }
+
+    // Samples that have not been taken yet belong to the replaced state and are dropped
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.power = r.bool()?;
+        self.nr50 = r.u8()?;
+        self.nr51 = r.u8()?;
+        self.square1.load_state(r)?;
+        self.square2.load_state(r)?;
+        self.wave.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.sequencer_step = r.u8()? & 7;
+        self.div_bit = r.bool()?;
+        self.sample_clock = r.u32()?;
+        for capacitor in &mut self.capacitor {
+            *capacitor = f32::from_bits(r.u32()?);
+        }
+        self.samples.clear();
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.square1.read(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.square2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.wave.read(addr - 0xFF1A),
+            0xFF1F..=0xFF23 => self.noise.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                0x70 | ((self.power as u8) << 7)
+                    | (self.square1.is_enabled() as u8)
+                    | ((self.square2.is_enabled() as u8) << 1)
+                    | ((self.wave.is_enabled() as u8) << 2)
+                    | ((self.noise.is_enabled() as u8) << 3)
+            }
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.wave.read_ram(addr),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF26 => {
+                if self.power && val & POWER == 0 {
+                    self.power_off();
+                } else if !self.power && val & POWER > 0 {
+                    self.sequencer_step = 0;
+                }
+                self.power = val & POWER > 0;
+            }
+            0xFF30..=0xFF3F => self.wave.write_ram(addr, val),
+            // the registers are read-only while the APU is off
+            _ if !self.power => {}
+            0xFF10..=0xFF14 => self.square1.write(addr - 0xFF10, val),
+            0xFF15..=0xFF19 => self.square2.write(addr - 0xFF15, val),
+            0xFF1A..=0xFF1E => self.wave.write(addr - 0xFF1A, val),
+            0xFF1F..=0xFF23 => self.noise.write(addr - 0xFF1F, val),
+            0xFF24 => self.nr50 = val,
+            0xFF25 => self.nr51 = val,
+            0xFF27..=0xFF2F => {}
+            _ => unreachable!(),
+        }
+    }
+
+    // turning the APU off clears every register except the wave RAM
+    fn power_off(&mut self) {
+        for addr in 0xFF10..=0xFF25 {
+            self.write(addr, 0);
+        }
+        self.square1 = Square::new(true);
+        self.square2 = Square::new(false);
+        self.noise = Noise::new();
+        let mut wave = Wave::new();
+        for addr in 0xFF30..=0xFF3F {
+            wave.write_ram(addr, self.wave.read_ram(addr));
+        }
+        self.wave = wave;
+    }
+
+    fn step_sequencer(&mut self) {
+        if self.sequencer_step & 1 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) & 7;
+    }
+
+    pub fn emulate_cycle(&mut self, div: u16) {
+        let div_bit = div & DIV_APU_BIT > 0;
+        if self.power {
+            if self.div_bit && !div_bit {
+                self.step_sequencer();
+            }
+            self.square1.emulate_cycle();
+            self.square2.emulate_cycle();
+            self.wave.emulate_cycle();
+            self.noise.emulate_cycle();
+        }
+        self.div_bit = div_bit;
+
+        self.sample_clock += SAMPLE_RATE;
+        if self.sample_clock >= M_CYCLE_HZ {
+            self.sample_clock -= M_CYCLE_HZ;
+            self.mix();
+        }
+    }
+
+    fn mix(&mut self) {
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.into_iter().enumerate() {
+            // the DAC maps 0..=15 to 1.0..=-1.0
+            let analog = match output {
+                Some(digital) => 1.0 - digital as f32 / 7.5,
+                None => 0.0,
+            };
+            if self.nr51 & (1 << (i + 4)) > 0 {
+                left += analog;
+            }
+            if self.nr51 & (1 << i) > 0 {
+                right += analog;
+            }
+        }
+        let left_volume = (((self.nr50 >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0b111) + 1) as f32 / 8.0;
+        let left = self.high_pass(0, left / 4.0 * left_volume);
+        let right = self.high_pass(1, right / 4.0 * right_volume);
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(left);
+            self.samples.push(right);
+        }
+    }
+
+    // removes the DC offset like the capacitor on the real hardware output
+    fn high_pass(&mut self, side: usize, input: f32) -> f32 {
+        let output = input - self.capacitor[side];
+        self.capacitor[side] = input - output * 0.996;
+        output
+    }
+
+    // Interleaved stereo samples at SAMPLE_RATE produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
@@ -0,0 +1,76 @@
+use super::super::super::state::{Reader, StateError, Writer};
+
+pub struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            initial: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u8(self.initial);
+        w.bool(self.increase);
+        w.u8(self.period);
+        w.u8(self.timer);
+        w.u8(self.volume);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.initial = r.u8()? & 0x0F;
+        self.increase = r.bool()?;
+        self.period = r.u8()? & 0b111;
+        self.timer = r.u8()?;
+        self.volume = r.u8()? & 0x0F;
+        Ok(())
+    }
+
+    pub fn read(&self) -> u8 {
+        (self.initial << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0b1000 > 0;
+        self.period = val & 0b111;
+    }
+
+    // the DAC is off when the upper 5 bits of NRx2 are all 0
+    pub fn dac_enabled(&self) -> bool {
+        self.read() & 0xF8 > 0
+    }
+
+    pub fn trigger(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+        self.volume = self.initial;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
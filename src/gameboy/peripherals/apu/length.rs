@@ -0,0 +1,51 @@
+use super::super::super::state::{Reader, StateError, Writer};
+
+pub struct Length {
+    max: u16,
+    counter: u16,
+    pub enabled: bool,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Self {
+        Self {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u16(self.counter);
+        w.bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.counter = r.u16()?;
+        if self.counter > self.max {
+            return Err(StateError::Invalid("length counter"));
+        }
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+
+    pub fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns true when the counter expires and the channel has to be disabled.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use super::super::super::state::{Reader, StateError, Writer};
+use super::envelope::Envelope;
+use super::length::Length;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    nr43: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            nr43: 0,
+            lfsr: 0x7FFF,
+            timer: 8,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.nr43);
+        w.u16(self.lfsr);
+        w.u32(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.nr43 = r.u8()?;
+        self.lfsr = r.u16()? & 0x7FFF;
+        self.timer = r.u32()?;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // reg is the offset from the unused NR40
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            2 => self.envelope.read(),
+            3 => self.nr43,
+            4 => 0xBF | ((self.length.enabled as u8) << 6),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {}
+            1 => self.length.load(val & 0x3F),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.nr43 = val,
+            4 => {
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.nr43 & 0b111) as usize] << (self.nr43 >> 4)
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn emulate_cycle(&mut self) {
+        // the period is always a multiple of 8 T-cycles
+        if self.timer > 4 {
+            self.timer -= 4;
+            return;
+        }
+        self.timer = self.period();
+        // shifts 14 and 15 stop the LFSR
+        if self.nr43 >> 4 >= 14 {
+            return;
+        }
+        let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+        self.lfsr = (self.lfsr >> 1) | (xor << 14);
+        if self.nr43 & 0b1000 > 0 {
+            // 7-bit mode
+            self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+        }
+    }
+
+    // Digital output from 0 to 15, or None when the DAC is off.
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if self.enabled && self.lfsr & 1 == 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use super::super::super::state::{Reader, StateError, Writer};
+use super::envelope::Envelope;
+use super::length::Length;
+
+const DUTY_TABLE: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+pub struct Square {
+    enabled: bool,
+    has_sweep: bool,
+    sweep: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_freq: u16,
+    duty: u8,
+    duty_pos: u8,
+    length: Length,
+    envelope: Envelope,
+    freq: u16,
+    timer: u16,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            has_sweep,
+            sweep: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_freq: 0,
+            duty: 0,
+            duty_pos: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            freq: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+        w.u8(self.sweep);
+        w.u8(self.sweep_timer);
+        w.bool(self.sweep_enabled);
+        w.u16(self.shadow_freq);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u16(self.freq);
+        w.u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.sweep = r.u8()? & 0x7F;
+        self.sweep_timer = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.shadow_freq = r.u16()? & 0x7FF;
+        self.duty = r.u8()? & 0b11;
+        self.duty_pos = r.u8()? & 7;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.freq = r.u16()? & 0x7FF;
+        self.timer = r.u16()?;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // reg is the offset from NRx0
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 if self.has_sweep => 0x80 | self.sweep,
+            1 => 0x3F | (self.duty << 6),
+            2 => self.envelope.read(),
+            4 => 0xBF | ((self.length.enabled as u8) << 6),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                if self.has_sweep {
+                    self.sweep = val & 0x7F;
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3F);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0xFF) | (((val & 0b111) as u16) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.freq) * 4;
+        if self.has_sweep {
+            self.shadow_freq = self.freq;
+            self.sweep_timer = self.sweep_period();
+            self.sweep_enabled = self.sweep & 0b01110111 > 0;
+            if self.sweep_shift() > 0 {
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    fn sweep_period(&self) -> u8 {
+        match (self.sweep >> 4) & 0b111 {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    fn sweep_shift(&self) -> u8 {
+        self.sweep & 0b111
+    }
+
+    // the channel is disabled when the new frequency overflows
+    fn calculate_sweep(&mut self) -> u16 {
+        let delta = self.shadow_freq >> self.sweep_shift();
+        let freq = if self.sweep & 0b1000 > 0 {
+            self.shadow_freq - delta
+        } else {
+            self.shadow_freq + delta
+        };
+        if freq > 2047 {
+            self.enabled = false;
+        }
+        freq
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.sweep_timer = self.sweep_period();
+        if self.sweep_enabled && (self.sweep >> 4) & 0b111 > 0 {
+            let freq = self.calculate_sweep();
+            if freq <= 2047 && self.sweep_shift() > 0 {
+                self.freq = freq;
+                self.shadow_freq = freq;
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn emulate_cycle(&mut self) {
+        // one M-cycle is 4 T-cycles and the period is always a multiple of 4
+        if self.timer <= 4 {
+            self.timer = (2048 - self.freq) * 4;
+            self.duty_pos = (self.duty_pos + 1) & 7;
+        } else {
+            self.timer -= 4;
+        }
+    }
+
+    // Digital output from 0 to 15, or None when the DAC is off.
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if self.enabled && (DUTY_TABLE[self.duty as usize] >> self.duty_pos) & 1 > 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
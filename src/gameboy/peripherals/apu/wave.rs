@@ -0,0 +1,145 @@
+use super::super::super::state::{Reader, StateError, Writer};
+use super::length::Length;
+
+pub struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    volume: u8,
+    freq: u16,
+    timer: u16,
+    pos: u8,
+    sample: u8,
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume: 0,
+            freq: 0,
+            timer: 0,
+            pos: 0,
+            sample: 0,
+            ram: [0; 0x10],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        self.length.save_state(w);
+        w.u8(self.volume);
+        w.u16(self.freq);
+        w.u16(self.timer);
+        w.u8(self.pos);
+        w.u8(self.sample);
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.volume = r.u8()? & 0b11;
+        self.freq = r.u16()? & 0x7FF;
+        self.timer = r.u16()?;
+        self.pos = r.u8()? & 31;
+        self.sample = r.u8()? & 0x0F;
+        self.ram = r.array()?;
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // reg is the offset from NR30
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0x7F | ((self.dac_enabled as u8) << 7),
+            2 => 0x9F | (self.volume << 5),
+            4 => 0xBF | ((self.length.enabled as u8) << 6),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(val),
+            2 => self.volume = (val >> 5) & 0b11,
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            4 => {
+                self.freq = (self.freq & 0xFF) | (((val & 0b111) as u16) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = (2048 - self.freq) * 2;
+                    self.pos = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[addr as usize & 0xF]
+    }
+
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        self.ram[addr as usize & 0xF] = val;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn emulate_cycle(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        // the period is a multiple of 2 T-cycles, so it can elapse twice in one M-cycle
+        let mut cycles = 4;
+        while cycles > 0 {
+            let elapsed = cycles.min(self.timer);
+            self.timer -= elapsed;
+            cycles -= elapsed;
+            if self.timer == 0 {
+                self.timer = (2048 - self.freq) * 2;
+                self.pos = (self.pos + 1) & 31;
+                let byte = self.ram[self.pos as usize >> 1];
+                self.sample = if self.pos & 1 == 0 {
+                    byte >> 4
+                } else {
+                    byte & 0xF
+                };
+            }
+        }
+    }
+
+    // Digital output from 0 to 15, or None when the DAC is off.
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some(match self.volume {
+            0 => 0,
+            volume => self.sample >> (volume - 1),
+        })
+    }
+}
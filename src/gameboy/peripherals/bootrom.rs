@@ -9,8 +9,11 @@ impl Bootrom {
     pub fn is_active(&self) -> bool {
         self.active
     }
+    // Only the mapping is part of save states, the ROM itself is supplied by the user
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
     pub fn write(&mut self, _: u16, val: u8) {
-        // println!("Bootrom:write {}", val);
         self.active = val == 0;
     }
     pub fn read(&self, addr: u16) -> u8 {
@@ -0,0 +1,77 @@
+use super::super::state::{Reader, StateError, Writer};
+
+pub struct Dma {
+    source: u8,
+    active: bool,
+    delay: u8,
+    idx: u8,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self {
+            source: 0xFF,
+            active: false,
+            delay: 0,
+            idx: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u8(self.source);
+        w.bool(self.active);
+        w.u8(self.delay);
+        w.u8(self.idx);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.source = r.u8()?;
+        self.active = r.bool()?;
+        self.delay = r.u8()?;
+        self.idx = r.u8()?;
+        // a finished transfer is left at 0xA0
+        if self.active && self.idx as usize >= 0xA0 {
+            return Err(StateError::Invalid("OAM DMA index"));
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, _: u16) -> u8 {
+        self.source
+    }
+
+    pub fn write(&mut self, _: u16, val: u8) {
+        // writing again restarts the transfer
+        self.source = val;
+        self.active = true;
+        self.delay = 1;
+        self.idx = 0;
+    }
+
+    // Returns the source address and the OAM index of the byte to copy in this cycle.
+    pub fn emulate_cycle(&mut self) -> Option<(u16, usize)> {
+        if !self.active {
+            return None;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return None;
+        }
+        // 0xE000-0xFFFF is mirrored to WRAM
+        let page = if self.source >= 0xE0 {
+            self.source - 0x20
+        } else {
+            self.source
+        };
+        let ret = (((page as u16) << 8) | self.idx as u16, self.idx as usize);
+        self.idx += 1;
+        if self.idx as usize == 0xA0 {
+            self.active = false;
+        }
+        Some(ret)
+    }
+}
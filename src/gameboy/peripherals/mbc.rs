@@ -1,3 +1,8 @@
+mod rtc;
+
+pub use self::rtc::Rtc;
+use super::super::state::{Reader, StateError, Writer};
+
 pub enum Mbc {
     NoMbc,
     Mbc1 {
@@ -6,12 +11,34 @@ pub enum Mbc {
         high_bank: usize,
         bank_mode: bool,
         rom_banks: usize,
+        // MBC1M leaves bit 4 of the low bank unconnected and wires the high bank one bit lower
+        multicart: bool,
+    },
+    Mbc2 {
+        sram_enable: bool,
+        rom_bank: usize,
+    },
+    Mbc3 {
+        // also enables the RTC registers
+        sram_enable: bool,
+        rom_bank: usize,
+        // 0x00-0x03 selects a RAM bank, 0x08-0x0C an RTC register
+        ram_select: usize,
+        latch: u8,
+        rtc: Option<Rtc>,
+    },
+    Mbc5 {
+        sram_enable: bool,
+        rom_bank: usize,
+        ram_bank: usize,
+        // rumble carts drive the motor with bit 3 of the RAM bank register
+        rumble: Option<bool>,
     },
 }
 
 impl Mbc {
-    pub fn new(cartridge_type: u8, rom_banks: usize) -> Self {
-        match cartridge_type {
+    pub fn new(cartridge_type: u8, rom_banks: usize, multicart: bool) -> Option<Self> {
+        Some(match cartridge_type {
             0x00 | 0x08 | 0x09 => Self::NoMbc,
             0x01..=0x03 => Self::Mbc1 {
                 sram_enable: false,
@@ -19,9 +46,27 @@ impl Mbc {
                 high_bank: 0b00,
                 bank_mode: false,
                 rom_banks,
+                multicart,
             },
-            _ => panic!("Not supported: {:02x}", cartridge_type),
-        }
+            0x05 | 0x06 => Self::Mbc2 {
+                sram_enable: false,
+                rom_bank: 1,
+            },
+            0x0F..=0x13 => Self::Mbc3 {
+                sram_enable: false,
+                rom_bank: 1,
+                ram_select: 0,
+                latch: 0xFF,
+                rtc: matches!(cartridge_type, 0x0F | 0x10).then(Rtc::new),
+            },
+            0x19..=0x1E => Self::Mbc5 {
+                sram_enable: false,
+                rom_bank: 1,
+                ram_bank: 0,
+                rumble: (cartridge_type >= 0x1C).then_some(false),
+            },
+            _ => return None,
+        })
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
@@ -46,6 +91,252 @@ impl Mbc {
                 0x6000..=0x7FFF => *bank_mode = val & 0b1 > 0,
                 _ => unreachable!(),
             },
+            // address bit 8 selects the register
+            Self::Mbc2 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+            } => match addr {
+                0x0000..=0x3FFF => {
+                    if addr & 0x100 == 0 {
+                        *sram_enable = val & 0xF == 0xA;
+                    } else {
+                        *rom_bank = ((val & 0xF) as usize).max(1);
+                    }
+                }
+                0x4000..=0x7FFF => {}
+                _ => unreachable!(),
+            },
+            Self::Mbc3 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+                ref mut ram_select,
+                ref mut latch,
+                ref mut rtc,
+            } => match addr {
+                0x0000..=0x1FFF => *sram_enable = val & 0xF == 0xA,
+                0x2000..=0x3FFF => *rom_bank = ((val & 0x7F) as usize).max(1),
+                0x4000..=0x5FFF => *ram_select = val as usize,
+                0x6000..=0x7FFF => {
+                    if *latch == 0x00
+                        && val == 0x01
+                        && let Some(rtc) = rtc
+                    {
+                        rtc.latch();
+                    }
+                    *latch = val;
+                }
+                _ => unreachable!(),
+            },
+            Self::Mbc5 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+                ref mut ram_bank,
+                ref mut rumble,
+            } => match addr {
+                0x0000..=0x1FFF => *sram_enable = val & 0xF == 0xA,
+                0x2000..=0x2FFF => *rom_bank = (*rom_bank & 0x100) | val as usize,
+                0x3000..=0x3FFF => *rom_bank = ((val & 0b1) as usize) << 8 | (*rom_bank & 0xFF),
+                0x4000..=0x5FFF => match rumble {
+                    Some(motor) => {
+                        *motor = val & 0b1000 > 0;
+                        *ram_bank = (val & 0b0111) as usize;
+                    }
+                    None => *ram_bank = (val & 0b1111) as usize,
+                },
+                0x6000..=0x7FFF => {}
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    // Only the registers, the type of MBC is given by the cartridge the state is loaded into
+    pub fn save_state(&self, w: &mut Writer) {
+        match *self {
+            Self::NoMbc => {}
+            Self::Mbc1 {
+                sram_enable,
+                low_bank,
+                high_bank,
+                bank_mode,
+                ..
+            } => {
+                w.bool(sram_enable);
+                w.u8(low_bank as u8);
+                w.u8(high_bank as u8);
+                w.bool(bank_mode);
+            }
+            Self::Mbc2 {
+                sram_enable,
+                rom_bank,
+            } => {
+                w.bool(sram_enable);
+                w.u8(rom_bank as u8);
+            }
+            Self::Mbc3 {
+                sram_enable,
+                rom_bank,
+                ram_select,
+                latch,
+                ref rtc,
+            } => {
+                w.bool(sram_enable);
+                w.u8(rom_bank as u8);
+                w.u8(ram_select as u8);
+                w.u8(latch);
+                if let Some(rtc) = rtc {
+                    rtc.save_state(w);
+                }
+            }
+            Self::Mbc5 {
+                sram_enable,
+                rom_bank,
+                ram_bank,
+                rumble,
+            } => {
+                w.bool(sram_enable);
+                w.u16(rom_bank as u16);
+                w.u8(ram_bank as u8);
+                if let Some(motor) = rumble {
+                    w.bool(motor);
+                }
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        match *self {
+            Self::NoMbc => {}
+            Self::Mbc1 {
+                ref mut sram_enable,
+                ref mut low_bank,
+                ref mut high_bank,
+                ref mut bank_mode,
+                ..
+            } => {
+                *sram_enable = r.bool()?;
+                *low_bank = ((r.u8()? & 0b11111) as usize).max(1);
+                *high_bank = (r.u8()? & 0b11) as usize;
+                *bank_mode = r.bool()?;
+            }
+            Self::Mbc2 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+            } => {
+                *sram_enable = r.bool()?;
+                *rom_bank = ((r.u8()? & 0xF) as usize).max(1);
+            }
+            Self::Mbc3 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+                ref mut ram_select,
+                ref mut latch,
+                ref mut rtc,
+            } => {
+                *sram_enable = r.bool()?;
+                *rom_bank = ((r.u8()? & 0x7F) as usize).max(1);
+                *ram_select = r.u8()? as usize;
+                *latch = r.u8()?;
+                if let Some(rtc) = rtc {
+                    rtc.load_state(r)?;
+                }
+            }
+            Self::Mbc5 {
+                ref mut sram_enable,
+                ref mut rom_bank,
+                ref mut ram_bank,
+                ref mut rumble,
+            } => {
+                *sram_enable = r.bool()?;
+                *rom_bank = (r.u16()? & 0x1FF) as usize;
+                *ram_bank = (r.u8()? & 0b1111) as usize;
+                if let Some(motor) = rumble {
+                    *motor = r.bool()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::NoMbc => "No MBC",
+            Self::Mbc1 {
+                multicart: true, ..
+            } => "MBC1M",
+            Self::Mbc1 { .. } => "MBC1",
+            Self::Mbc2 { .. } => "MBC2",
+            Self::Mbc3 { rtc: Some(_), .. } => "MBC3+TIMER",
+            Self::Mbc3 { rtc: None, .. } => "MBC3",
+            Self::Mbc5 {
+                rumble: Some(_), ..
+            } => "MBC5+RUMBLE",
+            Self::Mbc5 { rumble: None, .. } => "MBC5",
+        }
+    }
+
+    pub fn sram_enabled(&self) -> bool {
+        match *self {
+            Self::NoMbc => true,
+            Self::Mbc1 { sram_enable, .. }
+            | Self::Mbc2 { sram_enable, .. }
+            | Self::Mbc3 { sram_enable, .. }
+            | Self::Mbc5 { sram_enable, .. } => sram_enable,
+        }
+    }
+
+    // MBC3 only maps SRAM for the bank selects 0x00-0x03, the RTC aside nothing answers to the
+    // others
+    pub fn sram_selected(&self) -> bool {
+        !matches!(
+            *self,
+            Self::Mbc3 {
+                ram_select: 0x04..,
+                ..
+            }
+        )
+    }
+
+    // MBC2 has 512 half-bytes of RAM built in regardless of the header
+    pub fn builtin_sram_size(&self) -> Option<usize> {
+        match *self {
+            Self::Mbc2 { .. } => Some(0x200),
+            _ => None,
+        }
+    }
+
+    pub fn rumble(&self) -> bool {
+        matches!(
+            *self,
+            Self::Mbc5 {
+                rumble: Some(true),
+                ..
+            }
+        )
+    }
+
+    // The RTC register selected for 0xA000-0xBFFF instead of SRAM, if any. Without a timer
+    // nothing answers there.
+    pub fn rtc_select(&self) -> Option<usize> {
+        match *self {
+            Self::Mbc3 {
+                ram_select: ram_select @ 0x08..=0x0C,
+                ..
+            } => Some(ram_select),
+            _ => None,
+        }
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        match *self {
+            Self::Mbc3 { ref rtc, .. } => rtc.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        match *self {
+            Self::Mbc3 { ref mut rtc, .. } => rtc.as_mut(),
+            _ => None,
         }
     }
 
@@ -57,27 +348,59 @@ impl Mbc {
                 high_bank,
                 bank_mode,
                 rom_banks,
+                multicart,
                 ..
-            } => match addr {
-                0x0000..=0x3FFF => {
-                    if bank_mode {
-                        (high_bank << 19) | (addr & 0x3FFF) as usize
-                    } else {
-                        (addr & 0x3FFF) as usize
+            } => {
+                let (low_bank, high_shift) = if multicart {
+                    (low_bank & 0b1111, 18)
+                } else {
+                    (low_bank, 19)
+                };
+                match addr {
+                    0x0000..=0x3FFF => {
+                        if bank_mode {
+                            (high_bank << high_shift) | (addr & 0x3FFF) as usize
+                        } else {
+                            (addr & 0x3FFF) as usize
+                        }
                     }
-                }
-                0x4000..=0x7FFF => {
-                    (high_bank << 19)
-                        | ((low_bank & (rom_banks - 1)) << 14)
-                        | (addr & 0x3FFF) as usize
-                }
-                0xA000..=0xBFFF => {
-                    if bank_mode {
-                        (high_bank << 13) | (addr & 0x1FFF) as usize
-                    } else {
-                        (addr & 0x1FFF) as usize
+                    0x4000..=0x7FFF => {
+                        (high_bank << high_shift)
+                            | ((low_bank & (rom_banks - 1)) << 14)
+                            | (addr & 0x3FFF) as usize
+                    }
+                    0xA000..=0xBFFF => {
+                        if bank_mode {
+                            (high_bank << 13) | (addr & 0x1FFF) as usize
+                        } else {
+                            (addr & 0x1FFF) as usize
+                        }
                     }
+                    _ => unreachable!(),
                 }
+            }
+            Self::Mbc2 { rom_bank, .. } => match addr {
+                0x0000..=0x3FFF => addr as usize,
+                0x4000..=0x7FFF => (rom_bank << 14) | (addr & 0x3FFF) as usize,
+                0xA000..=0xBFFF => (addr & 0x1FF) as usize,
+                _ => unreachable!(),
+            },
+            Self::Mbc3 {
+                rom_bank,
+                ram_select,
+                ..
+            } => match addr {
+                0x0000..=0x3FFF => addr as usize,
+                0x4000..=0x7FFF => (rom_bank << 14) | (addr & 0x3FFF) as usize,
+                0xA000..=0xBFFF => (ram_select << 13) | (addr & 0x1FFF) as usize,
+                _ => unreachable!(),
+            },
+            Self::Mbc5 {
+                rom_bank, ram_bank, ..
+            } => match addr {
+                0x0000..=0x3FFF => addr as usize,
+                0x4000..=0x7FFF => (rom_bank << 14) | (addr & 0x3FFF) as usize,
+                0xA000..=0xBFFF => (ram_bank << 13) | (addr & 0x1FFF) as usize,
                 _ => unreachable!(),
             },
         }
@@ -0,0 +1,329 @@
+use super::super::super::state::{Reader, StateError, Writer};
+use super::super::super::{CPU_CLOCK_HZ, M_CYCLE_CLOCK};
+use ::std::time::{SystemTime, UNIX_EPOCH};
+
+const M_CYCLE_HZ: u32 = (CPU_CLOCK_HZ / M_CYCLE_CLOCK) as u32;
+
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAY_LOW: usize = 3;
+const DAY_HIGH: usize = 4;
+
+const DAY_HIGH_BIT: u8 = 1 << 0;
+const HALT: u8 = 1 << 6;
+const DAY_CARRY: u8 = 1 << 7;
+
+// writable bits of each register
+const MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, DAY_CARRY | HALT | DAY_HIGH_BIT];
+
+// 5 registers and 5 latched registers as u32 followed by a 64-bit UNIX timestamp,
+// as stored after SRAM in .sav files by most emulators
+pub const SAVE_SIZE: usize = 48;
+// older files use a 32-bit timestamp
+const SAVE_SIZE_32: usize = 44;
+
+pub struct Rtc {
+    regs: [u8; 5],
+    latched: [u8; 5],
+    cycles: u32,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 5],
+            latched: [0; 5],
+            cycles: 0,
+        }
+    }
+
+    // Unlike save() this keeps the sub-second progress and ignores the wall clock
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bytes(&self.regs);
+        w.bytes(&self.latched);
+        w.u32(self.cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.regs = r.array()?;
+        self.latched = r.array()?;
+        for (reg, mask) in self
+            .regs
+            .iter_mut()
+            .chain(&mut self.latched)
+            .zip(MASKS.iter().cycle())
+        {
+            *reg &= mask;
+        }
+        self.cycles = r.u32()?;
+        if self.cycles >= M_CYCLE_HZ {
+            return Err(StateError::Invalid("RTC cycle count"));
+        }
+        Ok(())
+    }
+
+    // reg is the value written to 0x4000-0x5FFF, 0x08-0x0C
+    pub fn read(&self, reg: usize) -> u8 {
+        self.latched[reg - 0x08]
+    }
+
+    pub fn write(&mut self, reg: usize, val: u8) {
+        let idx = reg - 0x08;
+        if idx == SECONDS {
+            self.cycles = 0;
+        }
+        self.regs[idx] = val & MASKS[idx];
+        self.latched[idx] = self.regs[idx];
+    }
+
+    pub fn latch(&mut self) {
+        self.latched = self.regs;
+    }
+
+    pub fn emulate_cycle(&mut self) {
+        if self.regs[DAY_HIGH] & HALT > 0 {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles == M_CYCLE_HZ {
+            self.cycles = 0;
+            self.tick();
+        }
+    }
+
+    // Counters only wrap when they reach their limit exactly, so a value written out of range
+    // keeps counting until its bits overflow without carrying into the next register.
+    fn tick(&mut self) {
+        self.regs[SECONDS] = (self.regs[SECONDS] + 1) & MASKS[SECONDS];
+        if self.regs[SECONDS] != 60 {
+            return;
+        }
+        self.regs[SECONDS] = 0;
+        self.regs[MINUTES] = (self.regs[MINUTES] + 1) & MASKS[MINUTES];
+        if self.regs[MINUTES] != 60 {
+            return;
+        }
+        self.regs[MINUTES] = 0;
+        self.regs[HOURS] = (self.regs[HOURS] + 1) & MASKS[HOURS];
+        if self.regs[HOURS] != 24 {
+            return;
+        }
+        self.regs[HOURS] = 0;
+        let (day_low, overflow) = self.regs[DAY_LOW].overflowing_add(1);
+        self.regs[DAY_LOW] = day_low;
+        if overflow {
+            if self.regs[DAY_HIGH] & DAY_HIGH_BIT > 0 {
+                self.regs[DAY_HIGH] = (self.regs[DAY_HIGH] & !DAY_HIGH_BIT) | DAY_CARRY;
+            } else {
+                self.regs[DAY_HIGH] |= DAY_HIGH_BIT;
+            }
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        if self.regs[DAY_HIGH] & HALT > 0 {
+            return;
+        }
+        // out of range values take their detour one second at a time
+        while seconds > 0
+            && (self.regs[SECONDS] >= 60 || self.regs[MINUTES] >= 60 || self.regs[HOURS] >= 24)
+        {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let seconds = self.regs[SECONDS] as u64 + seconds;
+        let minutes = self.regs[MINUTES] as u64 + seconds / 60;
+        let hours = self.regs[HOURS] as u64 + minutes / 60;
+        let day_high = self.regs[DAY_HIGH] & DAY_HIGH_BIT;
+        let days = ((day_high as u64) << 8 | self.regs[DAY_LOW] as u64) + hours / 24;
+        self.regs[SECONDS] = (seconds % 60) as u8;
+        self.regs[MINUTES] = (minutes % 60) as u8;
+        self.regs[HOURS] = (hours % 24) as u8;
+        self.regs[DAY_LOW] = days as u8;
+        self.regs[DAY_HIGH] = (self.regs[DAY_HIGH] & !DAY_HIGH_BIT) | (days >> 8) as u8 & 1;
+        if days > 0x1FF {
+            self.regs[DAY_HIGH] |= DAY_CARRY;
+        }
+    }
+
+    pub fn save(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(SAVE_SIZE);
+        for reg in self.regs.iter().chain(self.latched.iter()) {
+            ret.extend_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        ret.extend_from_slice(&now().to_le_bytes());
+        ret
+    }
+
+    // Restores the registers and catches up with the time that passed since they were saved.
+    pub fn load(&mut self, data: &[u8]) {
+        let timestamp = match data.len() {
+            SAVE_SIZE => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            SAVE_SIZE_32 => u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64,
+            _ => return,
+        };
+        for i in 0..5 {
+            self.regs[i] = data[i * 4] & MASKS[i];
+            self.latched[i] = data[20 + i * 4] & MASKS[i];
+        }
+        // a year at most, a dead battery would not keep the clock running forever either
+        self.advance(now().saturating_sub(timestamp).min(366 * 24 * 60 * 60));
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(rtc: &mut Rtc, regs: [u8; 5]) {
+        for (i, val) in regs.into_iter().enumerate() {
+            rtc.write(0x08 + i, val);
+        }
+    }
+
+    fn run_second(rtc: &mut Rtc) {
+        for _ in 0..M_CYCLE_HZ {
+            rtc.emulate_cycle();
+        }
+        rtc.latch();
+    }
+
+    fn latched(rtc: &Rtc) -> [u8; 5] {
+        ::std::array::from_fn(|i| rtc.read(0x08 + i))
+    }
+
+    #[test]
+    fn ticks_once_per_second() {
+        let mut rtc = Rtc::new();
+        for _ in 0..M_CYCLE_HZ - 1 {
+            rtc.emulate_cycle();
+        }
+        rtc.latch();
+        assert_eq!(latched(&rtc), [0; 5]);
+        rtc.emulate_cycle();
+        rtc.latch();
+        assert_eq!(latched(&rtc), [1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn carries_into_the_next_register() {
+        let mut rtc = Rtc::new();
+        set(&mut rtc, [59, 59, 23, 0xFF, 0]);
+        run_second(&mut rtc);
+        assert_eq!(latched(&rtc), [0, 0, 0, 0, DAY_HIGH_BIT]);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_the_carry() {
+        let mut rtc = Rtc::new();
+        set(&mut rtc, [59, 59, 23, 0xFF, DAY_HIGH_BIT]);
+        run_second(&mut rtc);
+        assert_eq!(latched(&rtc), [0, 0, 0, 0, DAY_CARRY]);
+    }
+
+    #[test]
+    fn out_of_range_values_wrap_without_carrying() {
+        let mut rtc = Rtc::new();
+        set(&mut rtc, [63, 0, 0, 0, 0]);
+        run_second(&mut rtc);
+        assert_eq!(latched(&rtc), [0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn halt_stops_the_clock() {
+        let mut rtc = Rtc::new();
+        set(&mut rtc, [10, 0, 0, 0, HALT]);
+        run_second(&mut rtc);
+        rtc.advance(100);
+        rtc.latch();
+        assert_eq!(latched(&rtc), [10, 0, 0, 0, HALT]);
+    }
+
+    #[test]
+    fn advance_matches_ticking() {
+        let starts = [
+            [0, 0, 0, 0, 0],
+            [59, 59, 23, 0xFF, DAY_HIGH_BIT],
+            [30, 12, 5, 0x80, DAY_CARRY],
+            [62, 61, 30, 0x10, 0],
+            [0, 0, 25, 0xFF, DAY_HIGH_BIT],
+        ];
+        for regs in starts {
+            for seconds in [0, 1, 59, 61, 3599, 3601, 86399, 86401, 3 * 86400 + 4321] {
+                let mut ticked = Rtc::new();
+                set(&mut ticked, regs);
+                for _ in 0..seconds {
+                    ticked.tick();
+                }
+                let mut advanced = Rtc::new();
+                set(&mut advanced, regs);
+                advanced.advance(seconds);
+                assert_eq!(advanced.regs, ticked.regs, "{:?} + {}s", regs, seconds);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_return_the_latched_registers() {
+        let mut rtc = Rtc::new();
+        run_second(&mut rtc);
+        run_second(&mut rtc);
+        assert_eq!(rtc.read(0x08), 2);
+        for _ in 0..M_CYCLE_HZ {
+            rtc.emulate_cycle();
+        }
+        assert_eq!(rtc.read(0x08), 2);
+        rtc.latch();
+        assert_eq!(rtc.read(0x08), 3);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut rtc = Rtc::new();
+        set(&mut rtc, [1, 2, 3, 4, HALT | DAY_HIGH_BIT]);
+        let data = rtc.save();
+        assert_eq!(data.len(), SAVE_SIZE);
+        let mut loaded = Rtc::new();
+        loaded.load(&data);
+        assert_eq!(loaded.regs, rtc.regs);
+        assert_eq!(loaded.latched, rtc.latched);
+    }
+
+    #[test]
+    fn load_catches_up_with_the_wall_clock() {
+        let mut data = Vec::new();
+        for reg in [0u32, 0, 0, 0, 0, 0, 0, 0, 0, 0] {
+            data.extend_from_slice(&reg.to_le_bytes());
+        }
+        // a second may pass before load looks at the clock
+        let timestamp = now() - (60 * 60 + 60 + 1);
+        let mut rtc = Rtc::new();
+        let mut data_64 = data.clone();
+        data_64.extend_from_slice(&timestamp.to_le_bytes());
+        rtc.load(&data_64);
+        assert!(matches!(rtc.regs[..3], [1 | 2, 1, 1]));
+        // the older layout with a 32-bit timestamp
+        let mut rtc = Rtc::new();
+        data.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        assert_eq!(data.len(), SAVE_SIZE_32);
+        rtc.load(&data);
+        assert!(matches!(rtc.regs[..3], [1 | 2, 1, 1]));
+    }
+
+    #[test]
+    fn load_ignores_a_trailer_of_another_size() {
+        let mut rtc = Rtc::new();
+        rtc.load(&[0x3B; 40]);
+        assert_eq!(rtc.regs, [0; 5]);
+    }
+}
@@ -1,8 +1,6 @@
-mod lcd;
-
-use self::lcd::Lcd;
-use ::sdl2::Sdl;
-use ::std::iter;
+use super::super::cpu::interrupts;
+use super::super::cpu::interrupts::Interrupts;
+use super::super::state::{Reader, StateError, Writer};
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 enum Mode {
@@ -25,16 +23,32 @@ const SPRITE_SIZR: u8 = 1 << 2;
 const SPRITE_ENABLE: u8 = 1 << 1;
 const BG_WINDOW_ENABLE: u8 = 1 << 0;
 
+const SPRITE_BEHIND_BG: u8 = 1 << 7;
+const SPRITE_Y_FLIP: u8 = 1 << 6;
+const SPRITE_X_FLIP: u8 = 1 << 5;
+const SPRITE_PALETTE: u8 = 1 << 4;
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
 const LYC_EQ_LY_INT: u8 = 1 << 6;
 const OAM_SCAN_INT: u8 = 1 << 5;
 const BVLANK_INT: u8 = 1 << 4;
 const HBLANK_INT: u8 = 1 << 3;
 const LYC_EQ_LY: u8 = 1 << 2;
 
+#[derive(Copy, Clone)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile_idx: u8,
+    flags: u8,
+}
+
 pub struct Ppu {
     mode: Mode,
     lcdc: u8,
     stat: u8,
+    stat_line: bool,
     scy: u8,
     scx: u8,
     ly: u8,
@@ -47,16 +61,20 @@ pub struct Ppu {
     vram: Box<[u8; 0x2000]>,
     oam: Box<[u8; 0xA0]>,
     buffer: Box<[u8; LCD_PIXELS * 4]>,
+    bg_line: [u8; LCD_WIDTH],
+    window_triggered: bool,
+    window_line: u8,
+    sprites: Vec<Sprite>,
     cycles: u8,
-    lcd: lcd::Lcd,
 }
 
 impl Ppu {
-    pub fn new(sdl: &Sdl) -> Self {
+    pub fn new() -> Self {
         Self {
             mode: Mode::OamScan,
             lcdc: 0,
             stat: 0,
+            stat_line: false,
             scy: 0,
             scx: 0,
             ly: 0,
@@ -69,11 +87,91 @@ impl Ppu {
             vram: Box::new([0; 0x2000]),
             oam: Box::new([0; 0xA0]),
             buffer: Box::new([0; LCD_PIXELS * 4]),
+            bg_line: [0; LCD_WIDTH],
+            window_triggered: false,
+            window_line: 0,
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
             cycles: 20,
-            lcd: Lcd::new(sdl, 4),
         }
     }
 
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u8(self.mode as u8);
+        for val in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            w.u8(val);
+        }
+        w.bool(self.stat_line);
+        w.u8(self.cycles);
+        w.bytes(&self.vram[..]);
+        w.bytes(&self.oam[..]);
+        w.bytes(&self.buffer[..LCD_PIXELS]);
+        w.bytes(&self.bg_line);
+        w.bool(self.window_triggered);
+        w.u8(self.window_line);
+        w.u8(self.sprites.len() as u8);
+        for sprite in &self.sprites {
+            w.bytes(&[sprite.y, sprite.x, sprite.tile_idx, sprite.flags]);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.mode = match r.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        for val in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *val = r.u8()?;
+        }
+        if self.ly > 153 {
+            return Err(StateError::Invalid("LY"));
+        }
+        self.stat_line = r.bool()?;
+        self.cycles = r.u8()?;
+        // a whole line in HBlank or VBlank is the longest a mode lasts
+        if !(1..=114).contains(&self.cycles) {
+            return Err(StateError::Invalid("PPU cycles"));
+        }
+        self.vram.copy_from_slice(r.bytes(0x2000)?);
+        self.oam.copy_from_slice(r.bytes(0xA0)?);
+        self.buffer[..LCD_PIXELS].copy_from_slice(r.bytes(LCD_PIXELS)?);
+        self.bg_line = r.array()?;
+        self.window_triggered = r.bool()?;
+        self.window_line = r.u8()?;
+        let sprites = r.u8()? as usize;
+        if sprites > MAX_SPRITES_PER_LINE {
+            return Err(StateError::Invalid("sprite count"));
+        }
+        self.sprites.clear();
+        for _ in 0..sprites {
+            let [y, x, tile_idx, flags] = r.array()?;
+            self.sprites.push(Sprite {
+                y,
+                x,
+                tile_idx,
+                flags,
+            });
+        }
+        Ok(())
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         // 以下のようにrangeを定数化したい
         // const VRAM_ADDRESS_RANGE: std::ops::Range<u16> = 0x8000..0xA000;
@@ -98,7 +196,6 @@ impl Ppu {
             0xFF43 => self.scx,
             0xFF44 => self.ly,
             0xFF45 => self.lyc,
-            0xFF46 => 0xFF, // not implemented
             0xFF47 => self.bgp,
             0xFF48 => self.obp0,
             0xFF49 => self.obp1,
@@ -120,12 +217,29 @@ impl Ppu {
                     self.oam[addr as usize & 0xFF] = val;
                 }
             }
-            0xFF40 => self.lcdc = val,
+            0xFF40 => {
+                if self.lcdc & PPU_ENABLE > 0 && val & PPU_ENABLE == 0 {
+                    // LY and the mode read as 0 while the LCD is off
+                    self.ly = 0;
+                    self.window_triggered = false;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                    self.stat_line = false;
+                } else if self.lcdc & PPU_ENABLE == 0 && val & PPU_ENABLE > 0 {
+                    self.mode = Mode::OamScan;
+                    self.cycles = 20;
+                    self.check_lyc_eq_ly();
+                }
+                self.lcdc = val;
+            }
             0xFF41 => self.stat = (self.stat & LYC_EQ_LY) | (val & 0xF8), // なぜこうなる？
             0xFF42 => self.scy = val,
             0xFF43 => self.scx = val,
             0xFF44 => {} // cant access
-            0xFF45 => self.lyc = val,
+            0xFF45 => {
+                self.lyc = val;
+                self.check_lyc_eq_ly();
+            }
             0xFF47 => self.bgp = val,
             0xFF48 => self.obp0 = val,
             0xFF49 => self.obp1 = val,
@@ -135,6 +249,11 @@ impl Ppu {
         }
     }
 
+    // OAM DMA writes regardless of the mode
+    pub fn write_oam(&mut self, idx: usize, val: u8) {
+        self.oam[idx] = val;
+    }
+
     // todo: もっとわかりやすく
     pub fn get_pixel_from_tile(&self, tile_idx: usize, row: u8, col: u8) -> u8 {
         let r = (row * 2) as usize;
@@ -146,33 +265,131 @@ impl Ppu {
     }
 
     // todo: もっとわかりやすく
-    pub fn get_tile_idx_from_tile_map(&self, tile_map: bool, row: u8, col: u8) -> u8 {
+    pub fn get_tile_idx_from_tile_map(&self, tile_map: bool, row: u8, col: u8) -> usize {
         let start_addr: usize = 0x1800 | ((tile_map as usize) << 10);
         let ret = self.vram[start_addr | (((row as usize) << 5) + col as usize) & 0x3FF];
         if self.lcdc & TILE_DATA_ADDRESSING_MODE > 0 {
-            ret
+            ret as usize
         } else {
-            ((ret as i8 as i16) + 0x100) as u8
+            ((ret as i8 as i16) + 0x100) as usize
+        }
+    }
+
+    fn shade(palette: u8, pixel: u8) -> u8 {
+        match (palette >> (pixel << 1)) & 0b11 {
+            0b00 => 0xFF,
+            0b01 => 0xAA,
+            0b10 => 0x55,
+            _ => 0x00,
         }
     }
 
     fn render_bg(&mut self) {
         if self.lcdc & BG_WINDOW_ENABLE == 0 {
-            return;
+            // the background is blank but sprites are still drawn
+            self.bg_line = [0; LCD_WIDTH];
+        } else {
+            // the window starts at WX - 7 once LY has matched WY in this frame
+            let window = self.lcdc & WINDOW_ENABLE > 0 && self.window_triggered && self.wx < 167;
+            let y = self.ly.wrapping_add(self.scy);
+            for i in 0..LCD_WIDTH {
+                self.bg_line[i] = if window && i + 7 >= self.wx as usize {
+                    let x = (i + 7 - self.wx as usize) as u8;
+                    let tile_idx = self.get_tile_idx_from_tile_map(
+                        self.lcdc & WINDOW_TILE_MAP > 0,
+                        self.window_line >> 3,
+                        x >> 3,
+                    );
+                    self.get_pixel_from_tile(tile_idx, self.window_line & 7, x & 7)
+                } else {
+                    let x = (i as u8).wrapping_add(self.scx);
+                    let tile_idx = self.get_tile_idx_from_tile_map(
+                        self.lcdc & BG_TILE_MAP > 0,
+                        y >> 3,
+                        x >> 3,
+                    );
+                    self.get_pixel_from_tile(tile_idx, y & 7, x & 7)
+                };
+            }
+            // the internal line counter only advances on lines where the window was drawn
+            if window {
+                self.window_line += 1;
+            }
         }
-        let y = self.ly.wrapping_add(self.scy);
         for i in 0..LCD_WIDTH {
-            let x = (i as u8).wrapping_add(self.scx);
-            let tile_idx =
-                self.get_tile_idx_from_tile_map(self.lcdc & BG_TILE_MAP > 0, y >> 3, x >> 3);
-            let pixel = self.get_pixel_from_tile(tile_idx as usize, y & 7, x & 7);
-            self.buffer[LCD_WIDTH * self.ly as usize + i as usize] =
-                match (self.bgp >> (pixel << 1)) & 0b11 {
-                    0b00 => 0xFF,
-                    0b01 => 0xAA,
-                    0b10 => 0x55,
-                    _ => 0x00,
+            self.buffer[LCD_WIDTH * self.ly as usize + i] = Self::shade(self.bgp, self.bg_line[i]);
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & SPRITE_SIZR > 0 { 16 } else { 8 }
+    }
+
+    // selects the first 10 sprites in OAM order that overlap the current line
+    fn scan_oam(&mut self) {
+        self.sprites.clear();
+        let height = self.sprite_height();
+        for entry in self.oam.chunks_exact(4) {
+            let sprite = Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile_idx: entry[2],
+                flags: entry[3],
+            };
+            let top = sprite.y.wrapping_sub(16);
+            if self.ly.wrapping_sub(top) < height {
+                self.sprites.push(sprite);
+                if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+        // on DMG the sprite with the smaller X wins, then the one earlier in OAM
+        self.sprites.sort_by_key(|sprite| sprite.x);
+    }
+
+    fn render_sprites(&mut self) {
+        if self.lcdc & SPRITE_ENABLE == 0 {
+            return;
+        }
+        let height = self.sprite_height();
+        let mut drawn = [false; LCD_WIDTH];
+        for sprite in self.sprites.iter() {
+            let mut row = self.ly.wrapping_sub(sprite.y.wrapping_sub(16));
+            if sprite.flags & SPRITE_Y_FLIP > 0 {
+                row = height - 1 - row;
+            }
+            let tile_idx = if height == 16 {
+                (sprite.tile_idx & 0xFE) | (row >> 3)
+            } else {
+                sprite.tile_idx
+            };
+            let palette = if sprite.flags & SPRITE_PALETTE > 0 {
+                self.obp1
+            } else {
+                self.obp0
+            };
+            for col in 0..8 {
+                let i = (sprite.x as usize + col as usize).wrapping_sub(8);
+                if i >= LCD_WIDTH || drawn[i] {
+                    continue;
+                }
+                let c = if sprite.flags & SPRITE_X_FLIP > 0 {
+                    7 - col
+                } else {
+                    col
                 };
+                let pixel = self.get_pixel_from_tile(tile_idx as usize, row & 7, c);
+                if pixel == 0 {
+                    continue;
+                }
+                // an opaque pixel hides the sprites with lower priority even when the background wins
+                drawn[i] = true;
+                if sprite.flags & SPRITE_BEHIND_BG > 0 && self.bg_line[i] != 0 {
+                    continue;
+                }
+                self.buffer[LCD_WIDTH * self.ly as usize + i] = Self::shade(palette, pixel);
+            }
         }
     }
 
@@ -184,16 +401,36 @@ impl Ppu {
         }
     }
 
-    pub fn emulate_cycle(&mut self) {
+    // The STAT interrupt is requested only on the rising edge of the OR of the enabled sources,
+    // so a source becoming active while another one is already active does not request it again.
+    fn update_stat_line(&mut self, interrupts: &mut Interrupts) {
+        let line = (self.stat & LYC_EQ_LY_INT > 0 && self.stat & LYC_EQ_LY > 0)
+            || (self.stat & OAM_SCAN_INT > 0 && self.mode == Mode::OamScan)
+            || (self.stat & BVLANK_INT > 0 && self.mode == Mode::VBlank)
+            || (self.stat & HBLANK_INT > 0 && self.mode == Mode::HBlank);
+        if line && !self.stat_line {
+            interrupts.irq(interrupts::STAT);
+        }
+        self.stat_line = line;
+    }
+
+    // Returns true when a frame has been completed.
+    pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) -> bool {
         if self.lcdc & PPU_ENABLE == 0 {
-            return;
+            return false;
         }
 
+        let mut frame = false;
         self.cycles -= 1;
-        if self.cycles > 0 {
-            return;
+        if self.cycles == 0 {
+            frame = self.switch_mode(interrupts);
         }
+        self.update_stat_line(interrupts);
+        frame
+    }
 
+    fn switch_mode(&mut self, interrupts: &mut Interrupts) -> bool {
+        let mut frame = false;
         match self.mode {
             Mode::HBlank => {
                 self.ly += 1;
@@ -203,6 +440,7 @@ impl Ppu {
                 } else {
                     self.mode = Mode::VBlank;
                     self.cycles = 114;
+                    interrupts.irq(interrupts::VBLANK);
                 }
                 self.check_lyc_eq_ly();
             }
@@ -210,30 +448,40 @@ impl Ppu {
                 self.ly += 1;
                 if self.ly > 153 {
                     self.ly = 0;
+                    self.window_triggered = false;
+                    self.window_line = 0;
                     self.mode = Mode::OamScan;
                     self.cycles = 20;
-                    self.lcd.draw(self.pixel_buffer());
+                    frame = true;
                 } else {
                     self.cycles = 114;
                 }
                 self.check_lyc_eq_ly();
             }
             Mode::OamScan => {
+                if self.ly == self.wy {
+                    self.window_triggered = true;
+                }
+                self.scan_oam();
                 self.mode = Mode::Drawing;
                 self.cycles = 43;
             }
             Mode::Drawing => {
                 self.render_bg();
+                self.render_sprites();
                 self.mode = Mode::HBlank;
                 self.cycles = 51;
             }
         }
+        frame
+    }
+
+    // What the LCD shows while the system clock is stopped
+    pub fn blank(&mut self) {
+        self.buffer.fill(0xFF);
     }
 
-    fn pixel_buffer(&self) -> Box<[u8]> {
-        self.buffer
-            .iter()
-            .flat_map(|&e| [e, e, e])
-            .collect::<Box<[u8]>>()
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer[..LCD_PIXELS]
     }
 }
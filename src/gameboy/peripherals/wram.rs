@@ -1,8 +1,17 @@
+use super::super::state::{Reader, StateError, Writer};
+
 pub struct WRam(Box<[u8; 0x2000]>);
 impl WRam {
     pub fn new() -> Self {
         Self(Box::new([0; 0x2000]))
     }
+    pub fn save_state(&self, w: &mut Writer) {
+        w.bytes(&self.0[..]);
+    }
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.0.copy_from_slice(r.bytes(0x2000)?);
+        Ok(())
+    }
     pub fn read(&self, addr: u16) -> u8 {
         self.0[(addr as usize) & 0x1fff]
     }
@@ -0,0 +1,290 @@
+use ::std::collections::VecDeque;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindOptions {
+    // frames between snapshots
+    pub interval: u32,
+    // seconds of history to keep at most
+    pub length: u32,
+    // bytes of memory to use at most
+    pub budget: usize,
+}
+
+impl Default for RewindOptions {
+    fn default() -> Self {
+        Self {
+            interval: 2,
+            length: 60,
+            budget: 32 << 20,
+        }
+    }
+}
+
+pub enum Back<'a> {
+    // keep showing the current snapshot
+    Stay,
+    Restore(&'a [u8]),
+    // nothing older is left
+    Exhausted,
+}
+
+// Keeps the newest snapshot in full and every older one as the delta that turns its successor
+// back into it, so the oldest ones can be dropped without touching the rest.
+pub struct Rewind {
+    options: RewindOptions,
+    // frames emulated since the newest snapshot
+    frames: u32,
+    // frames stepped back since the newest snapshot was restored
+    behind: u32,
+    latest: Vec<u8>,
+    // oldest first
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    pub fn new(options: RewindOptions) -> Self {
+        Self {
+            options: RewindOptions {
+                interval: options.interval.max(1),
+                ..options
+            },
+            frames: 0,
+            behind: 0,
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    // Called once per emulated frame, returns whether a snapshot is due
+    pub fn frame(&mut self) -> bool {
+        self.frames += 1;
+        self.behind = 0;
+        self.latest.is_empty() || self.frames >= self.options.interval
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.latest.is_empty() {
+            let delta = encode(&self.latest, &state);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = state;
+        self.frames = 0;
+        // about 60 frames per second
+        let max_deltas = (self.options.length * 60 / self.options.interval) as usize;
+        while self.deltas.len() > max_deltas
+            || self.deltas_size + self.latest.len() > self.options.budget
+        {
+            let Some(delta) = self.deltas.pop_front() else {
+                break;
+            };
+            self.deltas_size -= delta.len();
+        }
+    }
+
+    // Steps one frame back in time, going to the previous snapshot every interval frames
+    pub fn back(&mut self) -> Back<'_> {
+        if self.latest.is_empty() {
+            return Back::Exhausted;
+        }
+        // the emulation has run past the newest snapshot
+        if self.frames > 0 {
+            self.frames = 0;
+            self.behind = 0;
+            return Back::Restore(&self.latest);
+        }
+        self.behind += 1;
+        if self.behind < self.options.interval {
+            return Back::Stay;
+        }
+        let Some(delta) = self.deltas.pop_back() else {
+            return Back::Exhausted;
+        };
+        self.behind = 0;
+        self.deltas_size -= delta.len();
+        self.latest = decode(&delta, &self.latest);
+        Back::Restore(&self.latest)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut ret = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        ret |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return ret;
+        }
+        shift += 7;
+    }
+}
+
+// The length of the older state, then the XOR of both states as runs of unchanged bytes and
+// runs of changed ones, each run count a varint and changed runs followed by their bytes.
+// Bytes past the end of the shorter state count as zero.
+fn encode(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+    let mut ret = Vec::new();
+    write_varint(&mut ret, older.len());
+    let mut i = 0;
+    while i < older.len() {
+        let start = i;
+        while i < older.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut ret, i - start);
+        let start = i;
+        while i < older.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut ret, i - start);
+        ret.extend((start..i).map(xor));
+    }
+    ret
+}
+
+fn decode(delta: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut ret = newer.to_vec();
+    ret.resize(len, 0);
+    let mut i = 0;
+    while i < len {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for byte in &mut ret[i..i + changed] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(older: &[u8], newer: &[u8]) {
+        assert_eq!(decode(&encode(older, newer), newer), older);
+    }
+
+    // A state of len bytes that differs from others of the same seed in a few places
+    fn state(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| if i % 7 == 0 { seed } else { i as u8 })
+            .collect()
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        // equal
+        round_trip(&state(100, 1), &state(100, 1));
+        // changed in place
+        round_trip(&state(100, 1), &state(100, 2));
+        round_trip(&[0xFF; 300], &[0x00; 300]);
+        // grown and shrunk
+        round_trip(&state(100, 1), &state(150, 2));
+        round_trip(&state(150, 1), &state(100, 2));
+        round_trip(&[], &state(10, 1));
+        round_trip(&state(10, 1), &[]);
+        // runs longer than a single varint byte
+        let mut long = vec![0; 1000];
+        long[500..800].fill(0xAA);
+        round_trip(&long, &[0; 1000]);
+    }
+
+    #[test]
+    fn equal_states_encode_small() {
+        assert!(encode(&state(10000, 1), &state(10000, 1)).len() < 8);
+    }
+
+    fn options(interval: u32, length: u32, budget: usize) -> RewindOptions {
+        RewindOptions {
+            interval,
+            length,
+            budget,
+        }
+    }
+
+    // Pushes a snapshot every interval frames
+    fn record(rewind: &mut Rewind, states: impl IntoIterator<Item = Vec<u8>>) {
+        for state in states {
+            while !rewind.frame() {}
+            rewind.push(state);
+        }
+    }
+
+    // Every snapshot that can still be restored, newest first
+    fn history(rewind: &mut Rewind) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        loop {
+            match rewind.back() {
+                Back::Stay => {}
+                Back::Restore(state) => ret.push(state.to_vec()),
+                Back::Exhausted => return ret,
+            }
+        }
+    }
+
+    #[test]
+    fn steps_back_through_every_snapshot() {
+        let mut rewind = Rewind::new(options(2, 60, usize::MAX));
+        let states = (0..10).map(|i| state(100 + i, i as u8)).collect::<Vec<_>>();
+        record(&mut rewind, states.clone());
+        // a frame past the newest snapshot restores it first
+        rewind.frame();
+        let expected = states.into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(history(&mut rewind), expected);
+    }
+
+    #[test]
+    fn stays_on_a_snapshot_for_interval_frames() {
+        let mut rewind = Rewind::new(options(3, 60, usize::MAX));
+        record(&mut rewind, [state(10, 1), state(10, 2)]);
+        assert!(matches!(rewind.back(), Back::Stay));
+        assert!(matches!(rewind.back(), Back::Stay));
+        assert!(matches!(rewind.back(), Back::Restore(s) if s == state(10, 1)));
+        assert!(matches!(rewind.back(), Back::Stay));
+    }
+
+    #[test]
+    fn length_limits_the_history() {
+        // 60 snapshots a second at one per frame
+        let mut rewind = Rewind::new(options(1, 1, usize::MAX));
+        let states = (0..100).map(|i| state(100, i as u8)).collect::<Vec<_>>();
+        record(&mut rewind, states.clone());
+        assert_eq!(rewind.deltas.len(), 60);
+        // the newest snapshot is the current one
+        let expected = states[39..99].iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(history(&mut rewind), expected);
+    }
+
+    #[test]
+    fn budget_limits_the_history() {
+        let budget = 2000;
+        let mut rewind = Rewind::new(options(1, 60, budget));
+        let states = (0..100).map(|i| vec![i as u8; 500]).collect::<Vec<_>>();
+        record(&mut rewind, states.clone());
+        assert!(rewind.deltas_size + rewind.latest.len() <= budget);
+        assert_eq!(
+            rewind.deltas_size,
+            rewind.deltas.iter().map(Vec::len).sum::<usize>()
+        );
+        let history = history(&mut rewind);
+        assert!(!history.is_empty());
+        assert!(history.iter().rev().eq(&states[99 - history.len()..99]));
+    }
+}
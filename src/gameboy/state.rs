@@ -0,0 +1,313 @@
+// Save state format, all integers little endian:
+//
+//   header   8 bytes  "GBSTATE\0"
+//            u16      format version
+//            u16      global checksum computed over the ROM, to refuse states of another cartridge
+//   sections until the end of the data, each
+//            4 bytes  tag
+//            u32      payload length
+//            payload
+//
+// Sections, in the order they are written:
+//   "CPU "  registers, interrupts, HALT, STOP, a lock-up by an illegal opcode and the progress
+//           of the in-flight instruction
+//   "PPU "  registers, mode, cycles, VRAM, OAM, the line being drawn and the frame buffer
+//   "APU "  registers and internal counters of every channel
+//   "TIMR"  DIV, TIMA, TMA, TAC and a pending TIMA overflow
+//   "DMA "  OAM DMA source and progress
+//   "JOYP"  selected button group and pressed buttons
+//   "WRAM", "HRAM"  memory contents
+//   "BOOT"  whether the boot ROM is mapped
+//   "CART"  MBC registers, SRAM and the RTC
+//
+// Unknown sections are skipped and every known one is required. Any change to a payload bumps
+// VERSION.
+use ::std::{error, fmt};
+
+const MAGIC: &[u8; 8] = b"GBSTATE\0";
+const VERSION: u16 = 4;
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    WrongCartridge,
+    Truncated,
+    MissingSection(String),
+    // a field holds a value the emulator cannot be in
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotAState => write!(f, "not a save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            Self::WrongCartridge => write!(f, "the save state belongs to another cartridge"),
+            Self::Truncated => write!(f, "the save state is truncated"),
+            Self::MissingSection(tag) => write!(f, "the save state has no {} section", tag),
+            Self::Invalid(what) => write!(f, "the save state has an invalid {}", what),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(checksum: u16) -> Self {
+        let mut ret = Self { buf: Vec::new() };
+        ret.bytes(MAGIC);
+        ret.u16(VERSION);
+        ret.u16(checksum);
+        ret
+    }
+
+    // The length is patched in once the payload has been written
+    pub fn section(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut Self)) {
+        self.bytes(tag);
+        let len_pos = self.buf.len();
+        self.u32(0);
+        f(self);
+        let len = (self.buf.len() - len_pos - 4) as u32;
+        self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// Reads the payload of one section
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (ret, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(ret)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+}
+
+pub struct Sections<'a> {
+    sections: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> Sections<'a> {
+    pub fn parse(data: &'a [u8], checksum: u16) -> Result<Self, StateError> {
+        if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        let mut header = Reader {
+            data: &data[MAGIC.len()..HEADER_SIZE],
+        };
+        let version = header.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if header.u16()? != checksum {
+            return Err(StateError::WrongCartridge);
+        }
+        let mut rest = Reader {
+            data: &data[HEADER_SIZE..],
+        };
+        let mut sections = Vec::new();
+        while !rest.data.is_empty() {
+            let tag = rest.bytes(4)?;
+            let len = rest.u32()? as usize;
+            sections.push((tag, rest.bytes(len)?));
+        }
+        Ok(Self { sections })
+    }
+
+    // Hands the payload of a section to f, which has to consume all of it
+    pub fn load(
+        &self,
+        tag: &[u8; 4],
+        f: impl FnOnce(&mut Reader<'a>) -> Result<(), StateError>,
+    ) -> Result<(), StateError> {
+        let tag_name = || String::from_utf8_lossy(tag).trim_end().to_string();
+        let &(_, data) = self
+            .sections
+            .iter()
+            .find(|(t, _)| *t == tag.as_slice())
+            .ok_or_else(|| StateError::MissingSection(tag_name()))?;
+        let mut reader = Reader { data };
+        f(&mut reader)?;
+        if !reader.data.is_empty() {
+            return Err(StateError::Invalid("section length"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUM: u16 = 0x1234;
+
+    fn state() -> Vec<u8> {
+        let mut w = Writer::new(CHECKSUM);
+        w.section(b"ONE ", |w| {
+            w.u8(0x12);
+            w.bool(true);
+            w.u16(0x3456);
+            w.u32(0x789A_BCDE);
+            w.bytes(b"xyz");
+        });
+        w.section(b"TWO ", |w| w.u8(0xFF));
+        w.finish()
+    }
+
+    fn load_one(r: &mut Reader) -> Result<(), StateError> {
+        assert_eq!(r.u8()?, 0x12);
+        assert!(r.bool()?);
+        assert_eq!(r.u16()?, 0x3456);
+        assert_eq!(r.u32()?, 0x789A_BCDE);
+        assert_eq!(r.bytes(3)?, b"xyz");
+        Ok(())
+    }
+
+    fn parse(data: &[u8]) -> Result<Sections<'_>, StateError> {
+        Sections::parse(data, CHECKSUM)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = state();
+        let sections = parse(&data).unwrap();
+        sections.load(b"ONE ", load_one).unwrap();
+        sections
+            .load(b"TWO ", |r| {
+                assert_eq!(r.u8()?, 0xFF);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert_eq!(parse(b"").err(), Some(StateError::NotAState));
+        assert_eq!(parse(b"GBSTATE\0").err(), Some(StateError::NotAState));
+        let mut data = state();
+        data[0] = b'X';
+        assert_eq!(parse(&data).err(), Some(StateError::NotAState));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = state();
+        data[8..10].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            parse(&data).err(),
+            Some(StateError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_other_cartridges() {
+        let data = state();
+        assert_eq!(
+            Sections::parse(&data, CHECKSUM + 1).err(),
+            Some(StateError::WrongCartridge)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = state();
+        // cut between sections, which only shows once a section is missing
+        let first_end = HEADER_SIZE + 8 + 11;
+        let sections = parse(&data[..first_end]).unwrap();
+        assert_eq!(
+            sections.load(b"TWO ", |_| Ok(())),
+            Err(StateError::MissingSection("TWO".to_string()))
+        );
+        for len in (HEADER_SIZE + 1..data.len()).filter(|&len| len != first_end) {
+            assert_eq!(
+                parse(&data[..len]).err(),
+                Some(StateError::Truncated),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn payload_has_to_be_consumed_exactly() {
+        let data = state();
+        let sections = parse(&data).unwrap();
+        assert_eq!(
+            sections.load(b"ONE ", |r| r.u8().map(|_| ())),
+            Err(StateError::Invalid("section length"))
+        );
+        assert_eq!(
+            sections.load(b"TWO ", |r| r.u16().map(|_| ())),
+            Err(StateError::Truncated)
+        );
+    }
+
+    #[test]
+    fn skips_unknown_sections_and_requires_known_ones() {
+        let mut data = state();
+        data.extend_from_slice(b"NEW \x01\x00\x00\x00\x00");
+        let sections = parse(&data).unwrap();
+        sections.load(b"ONE ", load_one).unwrap();
+        assert_eq!(
+            sections.load(b"CPU ", |_| Ok(())),
+            Err(StateError::MissingSection("CPU".to_string()))
+        );
+    }
+}
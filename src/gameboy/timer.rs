@@ -1,5 +1,6 @@
 use super::cpu::interrupts;
 use super::cpu::interrupts::Interrupts;
+use super::state::{Reader, StateError, Writer};
 
 #[derive(Default)]
 pub struct Timer {
@@ -30,6 +31,31 @@ impl Timer {
         }
     }
 
+    pub fn div(&self) -> u16 {
+        self.div
+    }
+
+    pub fn set_div(&mut self, div: u16) {
+        self.div = div;
+    }
+
+    pub fn save_state(&self, w: &mut Writer) {
+        w.u16(self.div);
+        w.u8(self.tima);
+        w.bool(self.overflow);
+        w.u8(self.tma);
+        w.u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.div = r.u16()?;
+        self.tima = r.u8()?;
+        self.overflow = r.bool()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0b111;
+        Ok(())
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF04 => (self.div >> 8) as u8,
@@ -0,0 +1,62 @@
+use ::std::{cell::Cell, ops::RangeInclusive};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+    // only accesses of this value hit when set
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, addr: u16, value: u8) -> bool {
+        let access = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        access && self.range.contains(&addr) && self.value.is_none_or(|v| v == value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    pub access: Access,
+    pub addr: u16,
+    // the value read, or about to be written
+    pub value: u8,
+    // address of the instruction that made the access
+    pub pc: u16,
+    // ROM or SRAM bank for cartridge addresses
+    pub bank: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+    // address of the in-flight instruction, kept up to date by GameBoy
+    pub pc: u16,
+    // reads only borrow the bus, so the first hit is kept in a Cell until it is taken
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    // Later hits are ignored until the pending one is taken
+    pub fn matches(&self, access: Access, addr: u16, value: u8) -> bool {
+        self.hit.get().is_none() && self.list.iter().any(|w| w.matches(access, addr, value))
+    }
+
+    pub fn record(&self, hit: WatchHit) {
+        self.hit.set(Some(hit));
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
@@ -0,0 +1,62 @@
+use super::png;
+use ::gameboy::{FRAME_M_CYCLES, GameBoy, LCD_HEIGHT, LCD_WIDTH};
+use ::std::{path::PathBuf, process::ExitCode};
+
+pub struct Options {
+    pub frames: u64,
+    // stop as soon as the byte at the address has the value, checked once per frame
+    pub until: Option<(u16, u8)>,
+    pub screenshot: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            frames: 600,
+            until: None,
+            screenshot: None,
+        }
+    }
+}
+
+// Exits with 0 when the frames ran out or the condition was met, 1 when the frames ran
+// out before the condition was met and 2 when the screenshot could not be written.
+pub fn run(gameboy: &mut GameBoy, options: &Options) -> ExitCode {
+    let mut frames = 0;
+    let mut met = false;
+    'running: while frames < options.frames {
+        // counted by cycles so that a ROM keeping the LCD off still ends
+        for _ in 0..FRAME_M_CYCLES {
+            gameboy.emulate_cycle();
+        }
+        frames += 1;
+        if let Some(lockup) = gameboy.take_lockup() {
+            println!("{}", lockup);
+        }
+        // nobody listens, but do not let the samples pile up
+        gameboy.take_audio_samples();
+        if let Some((addr, val)) = options.until
+            && gameboy.peek(addr) == val
+        {
+            met = true;
+            break 'running;
+        }
+    }
+
+    if let Some(path) = &options.screenshot {
+        let (width, height) = (LCD_WIDTH as u32, LCD_HEIGHT as u32);
+        if let Err(e) = png::write_grayscale(path, width, height, gameboy.frame_buffer()) {
+            eprintln!("failed to write {}: {}", path.display(), e);
+            return ExitCode::from(2);
+        }
+    }
+
+    println!("ran {} frames", frames);
+    match options.until {
+        Some((addr, val)) if !met => {
+            println!("{:04x} never became {:02x}", addr, val);
+            ExitCode::FAILURE
+        }
+        _ => ExitCode::SUCCESS,
+    }
+}
@@ -0,0 +1,387 @@
+use ::gameboy::{CartridgeHeader, global_checksum};
+use ::std::{fmt::Write, fs, path::Path, process::ExitCode};
+
+enum Value {
+    Str(String),
+    Num(u64),
+    // hex digits to print
+    Hex(u64, usize),
+    // raw header byte and its meaning
+    Code(u8, String),
+    Bool(bool),
+}
+
+// Prints every header field of the ROM, decoded, along with the results of the checksum and logo
+// checks. Fails only when the file cannot be read or is too short to have a header.
+pub fn run(path: &Path, json: bool) -> ExitCode {
+    let rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let header = match CartridgeHeader::parse(&rom) {
+        Ok(header) => header,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let fields = fields(&header, &rom);
+    if json {
+        println!("{}", to_json(&fields));
+    } else {
+        let width = fields.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        for (key, val) in &fields {
+            let val = match val {
+                Value::Str(s) => s.clone(),
+                Value::Num(n) => n.to_string(),
+                Value::Hex(n, digits) => format!("{:0digits$X}", n),
+                Value::Code(code, name) => format!("{:02X} ({})", code, name),
+                Value::Bool(b) => if *b { "ok" } else { "BAD" }.to_string(),
+            };
+            println!("{:width$}  {}", key, val);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn fields(header: &CartridgeHeader, rom: &[u8]) -> Vec<(&'static str, Value)> {
+    use Value::*;
+    let licensee = if header.old_licensee() == 0x33 {
+        Str(format!(
+            "{} ({})",
+            header.new_licensee(),
+            new_licensee_name(&header.new_licensee())
+        ))
+    } else {
+        Code(
+            header.old_licensee(),
+            old_licensee_name(header.old_licensee()).to_string(),
+        )
+    };
+    let cgb = match header.cgb_flag() {
+        0x80 => "CGB enhanced",
+        0xC0 => "CGB only",
+        _ => "DMG",
+    };
+    let sgb = if header.sgb_flag() == 0x03 {
+        "supported"
+    } else {
+        "not supported"
+    };
+    let rom_size = header
+        .rom_size()
+        .map_or("unknown".to_string(), |size| format!("{} KiB", size / 1024));
+    let sram_size = header
+        .sram_size()
+        .map_or("unknown".to_string(), |size| format!("{} KiB", size / 1024));
+    let destination = if header.destination() == 0x00 {
+        "Japan"
+    } else {
+        "overseas"
+    };
+    let global = global_checksum(rom);
+    vec![
+        ("title", Str(header.title())),
+        ("maker", Str(header.maker())),
+        ("cgb", Code(header.cgb_flag(), cgb.to_string())),
+        ("sgb", Code(header.sgb_flag(), sgb.to_string())),
+        ("licensee", licensee),
+        (
+            "cartridge_type",
+            Code(
+                header.cartridge_type(),
+                cartridge_type_name(header.cartridge_type()).to_string(),
+            ),
+        ),
+        ("rom_size", Code(header.rom_size_code(), rom_size)),
+        ("file_size", Num(rom.len() as u64)),
+        ("sram_size", Code(header.sram_size_code(), sram_size)),
+        (
+            "destination",
+            Code(header.destination(), destination.to_string()),
+        ),
+        ("version", Num(header.game_version() as u64)),
+        ("header_checksum", Hex(header.header_checksum() as u64, 2)),
+        ("header_checksum_computed", Hex(header.checksum() as u64, 2)),
+        (
+            "header_checksum_valid",
+            Bool(header.header_checksum() == header.checksum()),
+        ),
+        ("global_checksum", Hex(header.global_checksum() as u64, 4)),
+        ("global_checksum_computed", Hex(global as u64, 4)),
+        (
+            "global_checksum_valid",
+            Bool(header.global_checksum() == global),
+        ),
+        ("logo_valid", Bool(header.has_valid_logo())),
+    ]
+}
+
+// JSON string with the escapes it needs
+fn quote(ret: &mut String, s: &str) {
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(ret, "\\u{:04x}", c as u32).unwrap(),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+}
+
+fn to_json(fields: &[(&str, Value)]) -> String {
+    let mut ret = String::from("{");
+    for (i, (key, val)) in fields.iter().enumerate() {
+        if i > 0 {
+            ret.push_str(", ");
+        }
+        write!(ret, "\"{}\": ", key).unwrap();
+        match val {
+            Value::Str(s) => quote(&mut ret, s),
+            Value::Num(n) | Value::Hex(n, _) => write!(ret, "{}", n).unwrap(),
+            Value::Code(code, name) => {
+                write!(ret, "{{\"code\": {}, \"name\": ", code).unwrap();
+                quote(&mut ret, name);
+                ret.push('}');
+            }
+            Value::Bool(b) => write!(ret, "{}", b).unwrap(),
+        }
+    }
+    ret.push('}');
+    ret
+}
+
+fn cartridge_type_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
+}
+
+fn new_licensee_name(code: &str) -> &'static str {
+    match code {
+        "00" => "None",
+        "01" => "Nintendo R&D1",
+        "08" => "Capcom",
+        "13" => "Electronic Arts",
+        "18" => "Hudson Soft",
+        "19" => "b-ai",
+        "20" => "KSS",
+        "22" => "Planning Office WADA",
+        "24" => "PCM Complete",
+        "25" => "San-X",
+        "28" => "Kemco",
+        "29" => "SETA Corporation",
+        "30" => "Viacom",
+        "31" => "Nintendo",
+        "32" => "Bandai",
+        "33" => "Ocean Software/Acclaim Entertainment",
+        "34" => "Konami",
+        "35" => "HectorSoft",
+        "37" => "Taito",
+        "38" => "Hudson Soft",
+        "39" => "Banpresto",
+        "41" => "Ubi Soft",
+        "42" => "Atlus",
+        "44" => "Malibu Interactive",
+        "46" => "Angel",
+        "47" => "Bullet-Proof Software",
+        "49" => "Irem",
+        "50" => "Absolute",
+        "51" => "Acclaim Entertainment",
+        "52" => "Activision",
+        "53" => "Sammy USA Corporation",
+        "54" => "Konami",
+        "55" => "Hi Tech Expressions",
+        "56" => "LJN",
+        "57" => "Matchbox",
+        "58" => "Mattel",
+        "59" => "Milton Bradley Company",
+        "60" => "Titus Interactive",
+        "61" => "Virgin Games Ltd.",
+        "64" => "Lucasfilm Games",
+        "67" => "Ocean Software",
+        "69" => "Electronic Arts",
+        "70" => "Infogrames",
+        "71" => "Interplay Entertainment",
+        "72" => "Broderbund",
+        "73" => "Sculptured Software",
+        "75" => "The Sales Curve Limited",
+        "78" => "THQ",
+        "79" => "Accolade",
+        "80" => "Misawa Entertainment",
+        "83" => "lozc",
+        "86" => "Tokuma Shoten",
+        "87" => "Tsukuda Original",
+        "91" => "Chunsoft Co.",
+        "92" => "Video System",
+        "93" => "Ocean Software/Acclaim Entertainment",
+        "95" => "Varie",
+        "96" => "Yonezawa/s'pal",
+        "97" => "Kaneko",
+        "99" => "Pack-In-Video",
+        "9H" => "Bottom Up",
+        "A4" => "Konami (Yu-Gi-Oh!)",
+        "BL" => "MTO",
+        "DK" => "Kodansha",
+        _ => "unknown",
+    }
+}
+
+fn old_licensee_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "None",
+        0x01 | 0x31 => "Nintendo",
+        0x08 | 0x38 => "Capcom",
+        0x09 => "HOT-B",
+        0x0A | 0xE0 => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C | 0x6E => "Elite Systems",
+        0x13 | 0x69 => "EA (Electronic Arts)",
+        0x18 => "Hudson Soft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F | 0x4A | 0x61 => "Virgin Games Ltd.",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 => "Kemco",
+        0x29 => "SETA Corporation",
+        0x30 | 0x70 => "Infogrames",
+        0x32 | 0xA2 | 0xB2 => "Bandai",
+        0x34 | 0xA4 => "Konami",
+        0x35 => "HectorSoft",
+        0x39 | 0x9D | 0xD9 => "Banpresto",
+        0x3C => "Entertainment Interactive",
+        0x3E => "Gremlin",
+        0x41 => "Ubi Soft",
+        0x42 | 0xEB => "Atlus",
+        0x44 | 0x4D => "Malibu Interactive",
+        0x46 | 0xCF => "Angel",
+        0x47 => "Spectrum HoloByte",
+        0x49 => "Irem",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 | 0xB0 => "Acclaim Entertainment",
+        0x52 => "Activision",
+        0x53 => "Sammy USA Corporation",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 | 0xDB | 0xFF => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley Company",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C | 0xD6 => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus Interactive",
+        0x67 => "Ocean Software",
+        0x6F => "Electro Brain",
+        0x71 => "Interplay Entertainment",
+        0x72 | 0xAA => "Broderbund",
+        0x73 => "Sculptured Software",
+        0x75 => "The Sales Curve Limited",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "MicroProse",
+        0x7F | 0xC2 => "Kemco",
+        0x80 => "Misawa Entertainment",
+        0x83 => "LOZC G.",
+        0x86 | 0xC4 => "Tokuma Shoten",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai Corp.",
+        0x8E => "Ape Inc.",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft Co.",
+        0x92 => "Video System",
+        0x93 => "Tsubaraya Productions",
+        0x95 | 0xE3 => "Varie",
+        0x96 => "Yonezawa/S'Pal",
+        0x97 => "Kaneko",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB1 => "ASCII Corporation or Nexsoft",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 | 0xCE => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy Corporation",
+        0xC0 | 0xD0 => "Taito",
+        0xC3 => "Square",
+        0xC5 => "Data East",
+        0xC6 => "Tonkin House",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra Games",
+        0xCB => "VAP, Inc.",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xD1 => "SOFEL",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha Co.",
+        0xD7 => "Copya System",
+        0xDA => "Tomy",
+        0xDD => "Nippon Computer Systems",
+        0xDE => "Human Ent.",
+        0xDF => "Altron",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE5 => "Epoch",
+        0xE7 => "Athena",
+        0xE8 => "Asmik Ace Entertainment",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,49 @@
+use ::gameboy::{LCD_HEIGHT, LCD_WIDTH};
+use ::sdl2::{Sdl, pixels::PixelFormatEnum, render::Canvas, video::Window};
+
+pub struct Lcd {
+    canvas: Canvas<Window>,
+}
+
+impl Lcd {
+    pub fn new(sdl: &Sdl, scale: u32, fullscreen: bool) -> Result<Lcd, String> {
+        let video = sdl.video()?;
+        let mut window = video.window(
+            "gb-emu",
+            LCD_WIDTH as u32 * scale,
+            LCD_HEIGHT as u32 * scale,
+        );
+        window.position_centered().resizable();
+        if fullscreen {
+            window.fullscreen_desktop();
+        }
+        let window = window
+            .build()
+            .map_err(|e| format!("failed to create a window: {}", e))?;
+        let canvas = window
+            .into_canvas()
+            .build()
+            .map_err(|e| format!("failed to create canvas: {}", e))?;
+        Ok(Self { canvas })
+    }
+
+    // Takes one shade per pixel and draws it as RGB24
+    pub fn draw(&mut self, buffer: &[u8]) {
+        let pixels = buffer
+            .iter()
+            .flat_map(|&e| [e, e, e])
+            .collect::<Box<[u8]>>();
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, LCD_WIDTH as u32, LCD_HEIGHT as u32)
+            .expect("failed to create texture streaming");
+        texture
+            .update(None, &pixels, LCD_WIDTH * 3)
+            .expect("failed to update texture");
+        self.canvas.clear();
+        self.canvas
+            .copy(&texture, None, None)
+            .expect("failed to copy canvas");
+        self.canvas.present();
+    }
+}
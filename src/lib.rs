@@ -0,0 +1,8 @@
+mod gameboy;
+
+pub use self::gameboy::{
+    Access, Bootrom, Button, CPU_CLOCK_HZ, Cartridge, CartridgeError, CartridgeHeader,
+    FRAME_M_CYCLES, GameBoy, LCD_HEIGHT, LCD_PIXELS, LCD_WIDTH, Lockup, M_CYCLE_CLOCK,
+    M_CYCLE_NANOS, Registers, RewindOptions, SAMPLE_RATE, StateError, WatchHit, Watchpoint,
+    global_checksum,
+};
@@ -0,0 +1,69 @@
+use ::std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub const NAMES: [&str; 4] = ["error", "warn", "info", "debug"];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// Messages go to stderr so that they never mix with the output of commands like info
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            eprintln!("{}: {}", $level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Error, $($arg)*) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+pub(crate) use {debug, error, info, log, warning};
@@ -1,23 +1,271 @@
-mod gameboy;
+mod audio;
+mod cli;
+mod debugger;
+mod headless;
+mod info;
+mod lcd;
+mod log;
+mod png;
+mod save;
 
-use self::gameboy::{Bootrom, Cartridge, GameBoy};
-use ::std::{env, fs};
+use self::audio::Audio;
+use self::debugger::{Debugger, Poll};
+use self::lcd::Lcd;
+use self::log::{debug, error, info, warning};
+use ::gameboy::{
+    Bootrom, Button, Cartridge, FRAME_M_CYCLES, GameBoy, M_CYCLE_NANOS, RewindOptions,
+};
+use ::sdl2::{
+    Sdl,
+    event::Event,
+    keyboard::{Keycode, Mod},
+};
+use ::std::{env, fs, path::Path, process::ExitCode, thread, time};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Need 1 argument");
+const SAVE_FLUSH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+const PAUSED_POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+const FRAME_NANOS: f64 = (FRAME_M_CYCLES * M_CYCLE_NANOS) as f64;
+
+fn main() -> ExitCode {
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Info { rom, json }) => return info::run(&rom, json),
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("Try 'gameboy-emulator --help' for more information.");
+            return ExitCode::from(2);
+        }
+    };
+    log::set_level(options.log_level);
+    match start(&options) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
     }
-    let cartridge_path = &args[1];
+}
 
-    let bootrom_binary = fs::read("./dmg_bootrom.bin")
-        .expect("failed to read bootrom")
-        .into_boxed_slice();
-    let bootrom = Bootrom::new(bootrom_binary);
-    let cartridge_binary = fs::read(cartridge_path)
-        .expect("failed to read cartridge")
+fn load_cartridge(options: &cli::Options) -> Result<Cartridge, String> {
+    let path = &options.rom;
+    let binary = fs::read(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?
         .into_boxed_slice();
-    let cartridge = Cartridge::new(cartridge_binary);
+    let cartridge = if options.lenient {
+        Cartridge::from_bytes_lenient(binary).map(|(cartridge, warnings)| {
+            for e in warnings {
+                warning!("{}: {}", path.display(), e);
+            }
+            cartridge
+        })
+    } else {
+        Cartridge::from_bytes(binary)
+    };
+    let cartridge = cartridge.map_err(|e| format!("{}: {}", path.display(), e))?;
+    info!("Cartridge info {}", cartridge);
+    Ok(cartridge)
+}
+
+fn start(options: &cli::Options) -> Result<ExitCode, String> {
+    // without a boot ROM the emulation starts right at the cartridge entry point
+    let bootrom = match &options.bootrom {
+        Some(path) => {
+            let binary =
+                fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            if binary.len() != 0x100 {
+                return Err(format!(
+                    "{}: a DMG boot ROM is 256 bytes, got {}",
+                    path.display(),
+                    binary.len()
+                ));
+            }
+            Some(Bootrom::new(binary.into_boxed_slice()))
+        }
+        None => None,
+    };
+    let mut cartridge = load_cartridge(options)?;
+    if let Some(headless_options) = &options.headless {
+        // headless runs never touch the .sav file so that they are reproducible
+        let mut gameboy = GameBoy::new(bootrom, cartridge);
+        return Ok(headless::run(&mut gameboy, headless_options));
+    }
+
+    if let Some(dir) = &options.save_dir {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+    let save_path = save::path_for(&options.rom, options.save_dir.as_deref());
+    save::load(&mut cartridge, &save_path);
     let mut gameboy = GameBoy::new(bootrom, cartridge);
-    gameboy.run();
+    gameboy.set_rewind((options.rewind_budget > 0).then(|| RewindOptions {
+        budget: options.rewind_budget.saturating_mul(1 << 20),
+        ..RewindOptions::default()
+    }));
+    let sdl = sdl2::init()?;
+    let mut lcd = Lcd::new(&sdl, options.scale, options.fullscreen)?;
+    // a missing audio device is no reason not to play
+    let mut audio = Audio::new(&sdl)
+        .map_err(|e| warning!("running without sound: {}", e))
+        .ok();
+    run(
+        &sdl,
+        &mut gameboy,
+        &mut lcd,
+        audio.as_mut(),
+        &save_path,
+        options,
+    )?;
+    save::flush(gameboy.cartridge_mut(), &save_path);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(
+    sdl: &Sdl,
+    gameboy: &mut GameBoy,
+    lcd: &mut Lcd,
+    mut audio: Option<&mut Audio>,
+    save_path: &Path,
+    options: &cli::Options,
+) -> Result<(), String> {
+    let mut event_pump = sdl.event_pump()?;
+    let mut paused = options.paused;
+    let mut rewinding = false;
+    let mut debugger = options.debug.then(|| Debugger::new(true));
+    if let Some(debugger) = &debugger {
+        debugger.start(gameboy);
+    }
+    let mut last = time::Instant::now();
+    // emulated nanoseconds owed to the wall clock, scaled by the speed
+    let mut owed = 0.0;
+    let mut last_flush = time::Instant::now();
+    'running: loop {
+        if last_flush.elapsed() >= SAVE_FLUSH_INTERVAL {
+            save::flush(gameboy.cartridge_mut(), save_path);
+            last_flush = time::Instant::now();
+        }
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => {
+                    paused = !paused;
+                    debug!("{}", if paused { "paused" } else { "resumed" });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(key),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(slot) = state_slot(key) {
+                        let path = save::state_path(save_path, slot);
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            save::save_state(gameboy, &path);
+                        } else {
+                            save::load_state(gameboy, &path);
+                            lcd.draw(gameboy.frame_buffer());
+                        }
+                    } else if let Some(button) = key_to_button(key) {
+                        gameboy.press(button);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(button) = key_to_button(key) {
+                        gameboy.release(button);
+                    }
+                }
+                _ => (),
+            }
+        }
+        if let Some(debugger) = debugger.as_mut() {
+            match debugger.poll(gameboy) {
+                Poll::Quit => break 'running,
+                Poll::Redraw => lcd.draw(gameboy.frame_buffer()),
+                Poll::Idle => (),
+            }
+        }
+        // a stopped debugger holds the emulation like a pause
+        let stopped = paused || debugger.as_ref().is_some_and(Debugger::is_stopped);
+        let now = time::Instant::now();
+        if !stopped {
+            owed += (now - last).as_nanos() as f64 * options.speed;
+        }
+        last = now;
+        if stopped {
+            thread::sleep(PAUSED_POLL_INTERVAL);
+            continue;
+        }
+        if rewinding {
+            // history plays backwards at the emulation speed
+            while owed >= FRAME_NANOS {
+                owed -= FRAME_NANOS;
+                if !gameboy.rewind_frame() {
+                    owed = 0.0;
+                }
+                lcd.draw(gameboy.frame_buffer());
+            }
+            continue;
+        }
+        while owed >= M_CYCLE_NANOS as f64 {
+            if gameboy.emulate_cycle() {
+                lcd.draw(gameboy.frame_buffer());
+                let samples = gameboy.take_audio_samples();
+                if let Some(audio) = audio.as_mut() {
+                    audio.queue(&samples);
+                }
+            }
+            owed -= M_CYCLE_NANOS as f64;
+            if let Some(debugger) = debugger.as_mut() {
+                if debugger.check(gameboy) {
+                    owed = 0.0;
+                    break;
+                }
+            } else if let Some(lockup) = gameboy.take_lockup() {
+                error!("{}", lockup);
+            }
+        }
+    }
+    Ok(())
+}
+
+// F1 to F4 load a slot, with Shift they save to it
+fn state_slot(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        _ => None,
+    }
+}
+
+fn key_to_button(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::RShift => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
 }
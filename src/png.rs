@@ -0,0 +1,68 @@
+use ::std::{fs, io, path::Path};
+
+// Writes an 8-bit grayscale PNG. The image data is stored without compression.
+pub fn write_grayscale(path: &Path, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let mut raw = Vec::with_capacity((width as usize + 1) * height as usize);
+    for row in pixels.chunks_exact(width as usize) {
+        raw.push(0); // filter type: none
+        raw.extend_from_slice(row);
+    }
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 0, 0, 0, 0]); // bit depth, grayscale, deflate, no filter, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    fs::write(path, png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        ret.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        ret.extend_from_slice(&len.to_le_bytes());
+        ret.extend_from_slice(&(!len).to_le_bytes());
+        ret.extend_from_slice(block);
+    }
+    ret.extend_from_slice(&adler32(data).to_be_bytes());
+    ret
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,74 @@
+use super::log::{debug, error, info, warning};
+use ::gameboy::{Cartridge, GameBoy};
+use ::std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Next to the ROM unless a directory for saves is given
+pub fn path_for(rom: &Path, save_dir: Option<&Path>) -> PathBuf {
+    match (save_dir, rom.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name).with_extension("sav"),
+        _ => rom.with_extension("sav"),
+    }
+}
+
+// Save state slots sit next to the .sav file as .ss1, .ss2 and so on
+pub fn state_path(save_path: &Path, slot: u8) -> PathBuf {
+    save_path.with_extension(format!("ss{}", slot))
+}
+
+pub fn save_state(gameboy: &GameBoy, path: &Path) {
+    let tmp = path.with_extension("tmp");
+    match fs::write(&tmp, gameboy.save_state()).and_then(|_| fs::rename(&tmp, path)) {
+        Ok(()) => info!("saved state to {}", path.display()),
+        Err(e) => error!("failed to write {}: {}", path.display(), e),
+    }
+}
+
+pub fn load_state(gameboy: &mut GameBoy, path: &Path) {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warning!("no save state at {}", path.display());
+            return;
+        }
+        Err(e) => {
+            error!("failed to read {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match gameboy.load_state(&data) {
+        Ok(()) => info!("loaded state from {}", path.display()),
+        Err(e) => error!("{}: {}", path.display(), e),
+    }
+}
+
+// Loads the .sav file of a battery-backed cartridge if there is one
+pub fn load(cartridge: &mut Cartridge, path: &Path) {
+    if !cartridge.has_battery() {
+        return;
+    }
+    match fs::read(path) {
+        Ok(data) => {
+            debug!("loaded {}", path.display());
+            cartridge.load_save_data(&data);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("failed to read {}: {}", path.display(), e),
+    }
+}
+
+// Writes the .sav file if SRAM has been written since the last flush.
+// Cartridges with an RTC are always written to keep the timestamp current.
+pub fn flush(cartridge: &mut Cartridge, path: &Path) {
+    if !cartridge.has_battery() || !(cartridge.take_sram_dirty() || cartridge.has_rtc()) {
+        return;
+    }
+    // go through a temporary file so that a crash never leaves a truncated save behind
+    let tmp = path.with_extension("sav.tmp");
+    match fs::write(&tmp, cartridge.save_data()).and_then(|_| fs::rename(&tmp, path)) {
+        Ok(()) => debug!("wrote {}", path.display()),
+        Err(e) => error!("failed to write {}: {}", path.display(), e),
+    }
+}